@@ -1,6 +1,15 @@
 extern crate winres;
 
 fn main() {
+    // Shown in the About dialog so a report can be matched to the exact build
+    if let Ok(output) = std::process::Command::new("git").args(["rev-parse", "--short", "HEAD"]).output() {
+        if output.status.success() {
+            let hash = String::from_utf8_lossy(&output.stdout);
+            println!("cargo:rustc-env=BLUETRAY_GIT_HASH={}", hash.trim());
+        }
+    }
+    println!("cargo:rerun-if-changed=.git/HEAD");
+
     if cfg!(target_os = "windows") {
         let mut res = winres::WindowsResource::new();
         res.set_manifest(r#"
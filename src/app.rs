@@ -0,0 +1,520 @@
+use tracing::debug;
+use tray_icon::menu::MenuId;
+use windows::core::{GUID, HSTRING};
+
+use crate::bluetooth::{format_address, DeviceKind};
+use crate::config::RetryPolicy;
+use crate::connection::{contains_device_id, same_device_id, ConnectionManager};
+use crate::history::HistoryAction;
+use crate::ipc::{kind_label, DeviceListing, Request, Response};
+use crate::menu::{DeviceEntry, DeviceMenu};
+use crate::platform::BluetoothPlatform;
+
+// The part of the event loop's state that menu clicks act on
+pub struct AppState<'a, P: BluetoothPlatform> {
+    pub device_menu: &'a mut DeviceMenu,
+    pub manager: &'a mut ConnectionManager<P>,
+    pub retry: RetryPolicy,
+}
+
+// Items handle_menu_event knows about besides the devices
+pub struct MenuIds {
+    pub quit: MenuId,
+    pub disconnect_all: MenuId,
+}
+
+// What the event loop still has to do once a click was handled. Spawning tasks, toasts
+// and file writes stay out of the handler so tests can drive it.
+#[derive(Debug, PartialEq)]
+pub enum UiAction {
+    Quit,
+    // Spawn the connect task; the manager already has the device as connecting
+    Connect {
+        device_id: HSTRING,
+        kind: DeviceKind,
+        preferred_service: Option<GUID>,
+        retry: RetryPolicy,
+    },
+    Notify {
+        title: String,
+        message: String,
+    },
+    Record {
+        device_id: String,
+        name: String,
+        action: HistoryAction,
+    },
+    // The set of connected devices changed and should be saved
+    SaveConnected,
+    // List the paired devices again
+    Refresh,
+}
+
+// What an IPC request came to. A connect is answered once its result is in.
+#[derive(Debug, PartialEq)]
+pub enum RequestOutcome {
+    Reply(Response),
+    AwaitConnect(HSTRING),
+}
+
+// Handles Quit, "Disconnect all" and clicks on device items. None for any other item,
+// which the event loop handles itself.
+pub fn handle_menu_event<P: BluetoothPlatform>(
+    event_id: &MenuId,
+    ids: &MenuIds,
+    state: &mut AppState<P>,
+) -> Option<Vec<UiAction>> {
+    let mut actions = Vec::new();
+    if *event_id == ids.quit {
+        // The links are closed once, by the shutdown that follows. The saved list is left
+        // alone so the devices come back next start.
+        actions.push(UiAction::Quit);
+    } else if *event_id == ids.disconnect_all {
+        for device_id in state.manager.connected_ids() {
+            let name = state.device_menu.name(&HSTRING::from(&device_id));
+            actions.push(UiAction::Record {
+                device_id,
+                name,
+                action: HistoryAction::Disconnect,
+            });
+        }
+        let disconnected = state.manager.disconnect_all();
+        for entry in state.device_menu.devices.values_mut() {
+            entry.show_connected(false);
+            entry.clear_status();
+        }
+        actions.push(UiAction::SaveConnected);
+        if disconnected > 0 {
+            actions.push(notify("Disconnected", format!("Disconnected {} device(s)", disconnected)));
+        }
+    } else if let Some(entry) = state.device_menu.devices.get_mut(event_id) {
+        if entry.accept_click() {
+            toggle_device(state.manager, entry, state.retry, &mut actions);
+        } else {
+            debug!(device_id = %entry.device_id, "Ignoring repeat click");
+        }
+    } else {
+        return None;
+    }
+    Some(actions)
+}
+
+// Every device in the menu, sorted by name, as the pipe's list and "Copy all devices"
+// report them
+pub fn device_listing<P: BluetoothPlatform>(
+    device_menu: &DeviceMenu,
+    manager: &ConnectionManager<P>,
+) -> Vec<DeviceListing> {
+    let mut devices: Vec<DeviceListing> = device_menu
+        .devices
+        .values()
+        .map(|entry| DeviceListing {
+            id: entry.device_id.to_string(),
+            name: entry.name.clone(),
+            kind: kind_label(entry.kind).to_string(),
+            class: entry.class.label().to_string(),
+            address: entry.address.map(format_address),
+            connected: manager.is_connected(&entry.device_id.to_string()),
+            connectable: entry.connectable,
+            battery: entry.battery,
+        })
+        .collect();
+    devices.sort_by_key(|device| device.name.to_lowercase());
+    devices
+}
+
+// Handles a command sent by another bluetray over the pipe, acting on the menu the same
+// way a click would
+pub fn handle_request<P: BluetoothPlatform>(
+    request: Request,
+    state: &mut AppState<P>,
+    actions: &mut Vec<UiAction>,
+) -> RequestOutcome {
+    let (device, connect) = match request {
+        Request::List => {
+            let devices = device_listing(state.device_menu, state.manager);
+            return RequestOutcome::Reply(Response::devices(devices));
+        }
+        Request::Refresh => {
+            actions.push(UiAction::Refresh);
+            return RequestOutcome::Reply(Response::ok("Refreshing the device list"));
+        }
+        Request::Connect { device } => (device, true),
+        Request::Disconnect { device } => (device, false),
+    };
+
+    let Some(entry) = find_device(state.device_menu, &device) else {
+        return RequestOutcome::Reply(Response::error(format!("No paired device called {}", device)));
+    };
+    let connected = state.manager.is_connected(&entry.device_id.to_string());
+    match (connect, connected) {
+        (true, true) => RequestOutcome::Reply(Response::ok(format!("{} is already connected", entry.name))),
+        (true, false) => {
+            // Already connecting is fine too, the answer waits for that attempt
+            if let Some(connect) = start_connect(state.manager, entry, state.retry) {
+                actions.push(connect);
+            }
+            RequestOutcome::AwaitConnect(entry.device_id.clone())
+        }
+        (false, true) => {
+            toggle_device(state.manager, entry, state.retry, actions);
+            RequestOutcome::Reply(Response::ok(format!("Disconnected from {}", entry.name)))
+        }
+        (false, false) => RequestOutcome::Reply(Response::ok(format!("{} is not connected", entry.name))),
+    }
+}
+
+// By id, however it was cased or padded, then by name ignoring case
+fn find_device<'a>(device_menu: &'a mut DeviceMenu, wanted: &str) -> Option<&'a mut DeviceEntry> {
+    let menu_id = device_menu
+        .devices
+        .iter()
+        .find(|(_, entry)| same_device_id(&entry.device_id.to_string(), wanted))
+        .or_else(|| {
+            device_menu
+                .devices
+                .iter()
+                .find(|(_, entry)| entry.name.eq_ignore_ascii_case(wanted))
+        })
+        .map(|(menu_id, _)| menu_id.clone())?;
+    device_menu.devices.get_mut(&menu_id)
+}
+
+// Connect attempts started by "Reconnect all", so one summary can go out once they're done
+#[derive(Debug, Default)]
+pub struct ReconnectBatch {
+    pending: Vec<String>,
+    connected: usize,
+    failed: Vec<String>,
+}
+
+impl ReconnectBatch {
+    pub fn add(&mut self, device_ids: Vec<String>) {
+        self.pending.extend(device_ids);
+    }
+
+    // Counts a result for a device in the batch. Returns the summary once the last one is in.
+    pub fn record(&mut self, device_id: &str, name: &str, connected: bool) -> Option<String> {
+        let position = self.pending.iter().position(|pending| pending == device_id)?;
+        self.pending.swap_remove(position);
+        if connected {
+            self.connected += 1;
+        } else {
+            self.failed.push(name.to_string());
+        }
+        if !self.pending.is_empty() {
+            return None;
+        }
+
+        let mut summary = format!("Reconnected {} device(s)", self.connected);
+        if !self.failed.is_empty() {
+            summary.push_str(&format!("; could not reconnect {}", self.failed.join(", ")));
+        }
+        Some(summary)
+    }
+}
+
+// Starts connecting every device that should be connected but isn't: the candidates given,
+// and any whose connection was lost. Attempts queue up on the manager's connect slots.
+// Returns the connects to spawn and the ids they're for.
+pub fn reconnect_all<P: BluetoothPlatform>(
+    state: &mut AppState<P>,
+    candidates: &[String],
+) -> (Vec<UiAction>, Vec<String>) {
+    let mut actions = Vec::new();
+    let mut started = Vec::new();
+    for entry in state.device_menu.devices.values_mut() {
+        let device_id = entry.device_id.to_string();
+        if !entry.is_lost() && !contains_device_id(candidates, &device_id) {
+            continue;
+        }
+        if let Some(connect) = start_connect(state.manager, entry, state.retry) {
+            actions.push(connect);
+            started.push(device_id);
+        }
+    }
+    if started.is_empty() {
+        actions.push(notify("Reconnect all", "Everything is already connected".to_string()));
+    }
+    (actions, started)
+}
+
+// Disconnects a connected device, otherwise starts connecting to it
+pub fn toggle_device<P: BluetoothPlatform>(
+    manager: &mut ConnectionManager<P>,
+    entry: &mut DeviceEntry,
+    retry: RetryPolicy,
+    actions: &mut Vec<UiAction>,
+) {
+    let device_id = entry.device_id.to_string();
+    if manager.is_connected(&device_id) {
+        if manager.disconnect_device(&device_id) {
+            actions.push(notify("Disconnected", format!("Disconnected from {}", entry.name)));
+            actions.push(UiAction::Record {
+                device_id: device_id.clone(),
+                name: entry.name.clone(),
+                action: HistoryAction::Disconnect,
+            });
+            actions.push(UiAction::SaveConnected);
+        }
+        entry.clear_status();
+    } else if let Some(connect) = start_connect(manager, entry, retry) {
+        actions.push(connect);
+    } else {
+        debug!(%device_id, "Already connecting, ignoring toggle");
+    }
+
+    // Whatever happened, the item shows it
+    entry.show_connected(manager.is_connected(&device_id));
+}
+
+// Marks the device as connecting unless it's already connected or an attempt is in
+// flight, and returns the connect to spawn. The item stays disabled until the result
+// comes back, so a second click can't start another attempt.
+pub fn start_connect<P: BluetoothPlatform>(
+    manager: &mut ConnectionManager<P>,
+    entry: &mut DeviceEntry,
+    retry: RetryPolicy,
+) -> Option<UiAction> {
+    if !manager.begin_connect(&entry.device_id, entry.name.clone()) {
+        return None;
+    }
+    entry.set_connecting(true);
+    Some(UiAction::Connect {
+        device_id: entry.device_id.clone(),
+        kind: entry.kind,
+        preferred_service: entry.preferred_service,
+        retry,
+    })
+}
+
+fn notify(title: &str, message: String) -> UiAction {
+    UiAction::Notify {
+        title: title.to_string(),
+        message,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::platform::mock::{connect, paired, tray_menu, MockPlatform};
+
+    fn ids() -> MenuIds {
+        MenuIds {
+            quit: MenuId::new("quit"),
+            disconnect_all: MenuId::new("disconnect-all"),
+        }
+    }
+
+    fn setup() -> (DeviceMenu, ConnectionManager<MockPlatform>) {
+        let mut device_menu = DeviceMenu::new(tray_menu());
+        let mut manager = ConnectionManager::new(MockPlatform::default());
+        device_menu.sync(Ok(vec![paired("device-a"), paired("device-b")]), &mut manager);
+        (device_menu, manager)
+    }
+
+    fn click(
+        device_menu: &mut DeviceMenu,
+        manager: &mut ConnectionManager<MockPlatform>,
+        menu_id: &MenuId,
+    ) -> Option<Vec<UiAction>> {
+        let mut state = AppState {
+            device_menu,
+            manager,
+            retry: RetryPolicy::default(),
+        };
+        handle_menu_event(menu_id, &ids(), &mut state)
+    }
+
+    fn item_id(device_menu: &DeviceMenu, device_id: &str) -> MenuId {
+        device_menu.find(&HSTRING::from(device_id)).unwrap().item.id().clone()
+    }
+
+    #[test]
+    fn clicking_a_disconnected_device_starts_connecting() {
+        let (mut device_menu, mut manager) = setup();
+        let menu_id = item_id(&device_menu, "device-a");
+
+        let actions = click(&mut device_menu, &mut manager, &menu_id).unwrap();
+
+        assert!(matches!(
+            actions.as_slice(),
+            [UiAction::Connect { device_id, kind: DeviceKind::Classic, .. }] if device_id == "device-a"
+        ));
+        assert!(manager.is_connecting("device-a"));
+        assert!(!device_menu.find(&HSTRING::from("device-a")).unwrap().item.is_enabled());
+    }
+
+    #[tokio::test]
+    async fn clicking_a_connected_device_disconnects_it() {
+        let (mut device_menu, mut manager) = setup();
+        connect(&mut manager, &HSTRING::from("device-a")).await;
+        let menu_id = item_id(&device_menu, "device-a");
+
+        let actions = click(&mut device_menu, &mut manager, &menu_id).unwrap();
+
+        assert!(matches!(actions.first(), Some(UiAction::Notify { .. })));
+        assert!(actions.contains(&UiAction::Record {
+            device_id: "device-a".to_string(),
+            name: "device-a".to_string(),
+            action: HistoryAction::Disconnect,
+        }));
+        assert!(actions.contains(&UiAction::SaveConnected));
+        assert!(!manager.is_connected("device-a"));
+        assert!(!device_menu.find(&HSTRING::from("device-a")).unwrap().shows_connected());
+    }
+
+    #[test]
+    fn repeat_click_is_ignored() {
+        let (mut device_menu, mut manager) = setup();
+        let menu_id = item_id(&device_menu, "device-a");
+
+        click(&mut device_menu, &mut manager, &menu_id).unwrap();
+        let actions = click(&mut device_menu, &mut manager, &menu_id).unwrap();
+
+        assert!(actions.is_empty());
+        assert!(manager.is_connecting("device-a"));
+    }
+
+    #[tokio::test]
+    async fn quit_leaves_closing_the_links_to_shutdown() {
+        let (mut device_menu, mut manager) = setup();
+        connect(&mut manager, &HSTRING::from("device-a")).await;
+        connect(&mut manager, &HSTRING::from("device-b")).await;
+
+        let actions = click(&mut device_menu, &mut manager, &ids().quit).unwrap();
+        assert_eq!(actions, vec![UiAction::Quit]);
+        assert!(manager.platform().closed.borrow().is_empty());
+
+        // What shutdown does
+        assert_eq!(manager.disconnect_all(), 2);
+        assert!(!manager.has_connections());
+        assert_eq!(manager.platform().closed.borrow().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn disconnect_all_records_each_device() {
+        let (mut device_menu, mut manager) = setup();
+        connect(&mut manager, &HSTRING::from("device-a")).await;
+
+        let actions = click(&mut device_menu, &mut manager, &ids().disconnect_all).unwrap();
+
+        assert_eq!(
+            actions,
+            vec![
+                UiAction::Record {
+                    device_id: "device-a".to_string(),
+                    name: "device-a".to_string(),
+                    action: HistoryAction::Disconnect,
+                },
+                UiAction::SaveConnected,
+                UiAction::Notify {
+                    title: "Disconnected".to_string(),
+                    message: "Disconnected 1 device(s)".to_string(),
+                },
+            ]
+        );
+        assert!(!manager.has_connections());
+    }
+
+    #[test]
+    fn other_items_are_left_to_the_event_loop() {
+        let (mut device_menu, mut manager) = setup();
+
+        assert!(click(&mut device_menu, &mut manager, &MenuId::new("refresh")).is_none());
+    }
+
+    fn request(
+        device_menu: &mut DeviceMenu,
+        manager: &mut ConnectionManager<MockPlatform>,
+        request: Request,
+    ) -> (RequestOutcome, Vec<UiAction>) {
+        let mut state = AppState {
+            device_menu,
+            manager,
+            retry: RetryPolicy::default(),
+        };
+        let mut actions = Vec::new();
+        let outcome = handle_request(request, &mut state, &mut actions);
+        (outcome, actions)
+    }
+
+    #[test]
+    fn connect_request_waits_for_the_result() {
+        let (mut device_menu, mut manager) = setup();
+
+        let connect = Request::Connect { device: "DEVICE-B".into() };
+        let (outcome, actions) = request(&mut device_menu, &mut manager, connect);
+
+        assert_eq!(outcome, RequestOutcome::AwaitConnect(HSTRING::from("device-b")));
+        assert!(matches!(actions.as_slice(), [UiAction::Connect { device_id, .. }] if device_id == "device-b"));
+        assert!(manager.is_connecting("device-b"));
+    }
+
+    #[test]
+    fn request_finds_a_device_by_id_however_it_is_written() {
+        let (mut device_menu, mut manager) = setup();
+
+        let connect = Request::Connect { device: " Device-A\n".into() };
+        let (outcome, _) = request(&mut device_menu, &mut manager, connect);
+
+        assert_eq!(outcome, RequestOutcome::AwaitConnect(HSTRING::from("device-a")));
+        assert!(manager.is_connecting("device-a"));
+    }
+
+    #[test]
+    fn request_for_an_unknown_device_is_refused() {
+        let (mut device_menu, mut manager) = setup();
+
+        let disconnect = Request::Disconnect { device: "nope".into() };
+        let (outcome, actions) = request(&mut device_menu, &mut manager, disconnect);
+
+        assert_eq!(outcome, RequestOutcome::Reply(Response::error("No paired device called nope")));
+        assert!(actions.is_empty());
+    }
+
+    #[tokio::test]
+    async fn list_request_reports_connections() {
+        let (mut device_menu, mut manager) = setup();
+        connect(&mut manager, &HSTRING::from("device-a")).await;
+
+        let (outcome, _) = request(&mut device_menu, &mut manager, Request::List);
+
+        let RequestOutcome::Reply(Response { devices: Some(devices), .. }) = outcome else {
+            panic!("expected a device list, got {:?}", outcome);
+        };
+        let connected: Vec<_> = devices.iter().map(|device| (device.id.as_str(), device.connected)).collect();
+        assert_eq!(connected, vec![("device-a", true), ("device-b", false)]);
+    }
+
+    #[tokio::test]
+    async fn reconnect_all_skips_connected_devices() {
+        let (mut device_menu, mut manager) = setup();
+        connect(&mut manager, &HSTRING::from("device-a")).await;
+        let mut state = AppState {
+            device_menu: &mut device_menu,
+            manager: &mut manager,
+            retry: RetryPolicy::default(),
+        };
+
+        let candidates = vec!["device-a".to_string(), "device-b".to_string()];
+        let (actions, started) = reconnect_all(&mut state, &candidates);
+
+        assert_eq!(started, vec!["device-b".to_string()]);
+        assert!(matches!(actions.as_slice(), [UiAction::Connect { device_id, .. }] if device_id == "device-b"));
+    }
+
+    #[test]
+    fn reconnect_batch_sums_up_once_every_result_is_in() {
+        let mut batch = ReconnectBatch::default();
+        batch.add(vec!["device-a".to_string(), "device-b".to_string()]);
+
+        assert_eq!(batch.record("device-c", "C", true), None);
+        assert_eq!(batch.record("device-a", "A", true), None);
+        assert_eq!(
+            batch.record("device-b", "B", false).as_deref(),
+            Some("Reconnected 1 device(s); could not reconnect B")
+        );
+    }
+}
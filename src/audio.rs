@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+
+use tracing::{info, warn};
+use windows::core::{Error, Interface, GUID, HSTRING, PCWSTR};
+use windows::Devices::Enumeration::{DeviceInformation, DeviceInformationKind};
+use windows::Foundation::IReference;
+use windows::Media::Devices::{AudioDeviceRole, MediaDevice};
+use windows::Win32::Media::Audio::{eCommunications, eConsole, eMultimedia};
+use windows::Win32::System::Com::{CoCreateInstance, CoInitializeEx, CoUninitialize, CLSCTX_ALL, COINIT_MULTITHREADED};
+use windows_collections::IIterable;
+
+use policy_config::{set_default_endpoint, IPolicyConfig, CLSID_POLICY_CONFIG};
+
+use crate::tasks;
+
+const CONTAINER_ID_PROPERTY: &str = "System.Devices.ContainerId";
+const AEP_CONTAINER_ID_PROPERTY: &str = "System.Devices.Aep.ContainerId";
+
+// Vtable order matters, so every method is declared even though only one is called
+#[allow(non_snake_case, dead_code)]
+mod policy_config {
+    use std::ffi::c_void;
+
+    use windows::core::{IUnknown, IUnknown_Vtbl, GUID, HRESULT, PCWSTR};
+    use windows::Win32::Media::Audio::ERole;
+    use windows_core::interface;
+
+    // The undocumented interface the Sound control panel uses to change the default
+    // device; Windows has no public API for it
+    pub const CLSID_POLICY_CONFIG: GUID = GUID::from_u128(0x870af99c_171d_4f9e_af0d_e63df40c2bc9);
+
+    #[interface("f8679f50-850a-41cf-9c72-430f290290c8")]
+    pub unsafe trait IPolicyConfig: IUnknown {
+        fn GetMixFormat(&self, device: PCWSTR, format: *mut *mut c_void) -> HRESULT;
+        fn GetDeviceFormat(&self, device: PCWSTR, default: i32, format: *mut *mut c_void) -> HRESULT;
+        fn ResetDeviceFormat(&self, device: PCWSTR) -> HRESULT;
+        fn SetDeviceFormat(&self, device: PCWSTR, endpoint_format: *mut c_void, mix_format: *mut c_void) -> HRESULT;
+        fn GetProcessingPeriod(&self, device: PCWSTR, default: i32, period: *mut i64, min_period: *mut i64) -> HRESULT;
+        fn SetProcessingPeriod(&self, device: PCWSTR, period: *mut i64) -> HRESULT;
+        fn GetShareMode(&self, device: PCWSTR, mode: *mut c_void) -> HRESULT;
+        fn SetShareMode(&self, device: PCWSTR, mode: *mut c_void) -> HRESULT;
+        fn GetPropertyValue(&self, device: PCWSTR, store: i32, key: *const c_void, value: *mut c_void) -> HRESULT;
+        fn SetPropertyValue(&self, device: PCWSTR, store: i32, key: *const c_void, value: *mut c_void) -> HRESULT;
+        fn SetDefaultEndpoint(&self, device: PCWSTR, role: ERole) -> HRESULT;
+        fn SetEndpointVisibility(&self, device: PCWSTR, visible: i32) -> HRESULT;
+    }
+
+    // The generated methods are private to this module
+    pub unsafe fn set_default_endpoint(policy_config: &IPolicyConfig, device: PCWSTR, role: ERole) -> HRESULT {
+        policy_config.SetDefaultEndpoint(device, role)
+    }
+}
+
+// Remembers, per device, the output that was the default before the device took over, so
+// it can be put back once the device disconnects
+#[derive(Default)]
+pub struct AudioSwitcher {
+    // Device id -> (its endpoint, the default before it)
+    switched: HashMap<String, (HSTRING, HSTRING)>,
+}
+
+impl AudioSwitcher {
+    pub fn switched(&mut self, device_id: String, endpoint: HSTRING, previous: HSTRING) {
+        self.switched.insert(device_id, (endpoint, previous));
+    }
+
+    // Devices whose previous default is still waiting to be restored
+    pub fn device_ids(&self) -> Vec<String> {
+        self.switched.keys().cloned().collect()
+    }
+
+    // Restores the previous default in the background, unless the user picked another
+    // output in the meantime
+    pub fn restore(&mut self, device_id: &str) {
+        let Some((endpoint, previous)) = self.switched.remove(device_id) else {
+            return;
+        };
+        tasks::spawn_blocking(move || {
+            if default_render_endpoint().as_ref() != Some(&endpoint) {
+                return;
+            }
+            match set_default_render_endpoint(&previous) {
+                Ok(()) => info!(endpoint = %previous, "Restored default audio output"),
+                Err(e) => warn!(error = %e, "Failed to restore default audio output"),
+            }
+        });
+    }
+}
+
+// Makes the device's output the default one. Returns its endpoint and the previous
+// default, or None when the device has no active output or it already was the default.
+pub async fn make_default_output(device_id: &HSTRING) -> Result<Option<(HSTRING, HSTRING)>, Error> {
+    let Some(endpoint) = render_endpoint_for(device_id).await? else {
+        return Ok(None);
+    };
+    let previous = default_render_endpoint();
+    if previous.as_ref() == Some(&endpoint) {
+        return Ok(None);
+    }
+    let target = endpoint.clone();
+    tokio::task::spawn_blocking(move || set_default_render_endpoint(&target))
+        .await
+        .map_err(|e| Error::new(windows::Win32::Foundation::E_FAIL, e.to_string()))??;
+    info!(%device_id, %endpoint, "Made default audio output");
+    Ok(previous.map(|previous| (endpoint, previous)))
+}
+
+// Audio endpoints share a container id with the Bluetooth device they belong to
+async fn render_endpoint_for(device_id: &HSTRING) -> Result<Option<HSTRING>, Error> {
+    let Some(container_id) = bluetooth_container_id(device_id).await else {
+        return Ok(None);
+    };
+    let property = HSTRING::from(CONTAINER_ID_PROPERTY);
+    let operation = {
+        let properties = IIterable::<HSTRING>::from(vec![property.clone()]);
+        DeviceInformation::FindAllAsyncAqsFilterAndAdditionalProperties(&MediaDevice::GetAudioRenderSelector()?, &properties)?
+    };
+    for endpoint in operation.await? {
+        let matches = endpoint
+            .Properties()
+            .and_then(|properties| properties.Lookup(&property))
+            .and_then(|value| value.cast::<IReference<GUID>>()?.Value())
+            .is_ok_and(|id| id == container_id);
+        if matches {
+            return Ok(Some(endpoint.Id()?));
+        }
+    }
+    Ok(None)
+}
+
+async fn bluetooth_container_id(device_id: &HSTRING) -> Option<GUID> {
+    let property = HSTRING::from(AEP_CONTAINER_ID_PROPERTY);
+    let operation = {
+        let properties = IIterable::<HSTRING>::from(vec![property.clone()]);
+        DeviceInformation::CreateFromIdAsyncWithKindAndAdditionalProperties(
+            device_id,
+            &properties,
+            DeviceInformationKind::AssociationEndpoint,
+        )
+        .ok()?
+    };
+    let info = operation.await.ok()?;
+    let value = info.Properties().ok()?.Lookup(&property).ok()?;
+    value.cast::<IReference<GUID>>().ok()?.Value().ok()
+}
+
+fn default_render_endpoint() -> Option<HSTRING> {
+    MediaDevice::GetDefaultAudioRenderId(AudioDeviceRole::Default)
+        .ok()
+        .filter(|endpoint| !endpoint.is_empty())
+}
+
+// Blocking COM calls, so run this off the async workers
+fn set_default_render_endpoint(endpoint: &HSTRING) -> Result<(), Error> {
+    // WinRT hands out interface paths; the policy config wants the MMDevice id inside them,
+    // e.g. \\?\SWD#MMDEVAPI#{0.0.0.00000000}.{...}#{...}
+    let endpoint = endpoint.to_string();
+    let mm_device_id = endpoint
+        .split_once("MMDEVAPI#")
+        .and_then(|(_, rest)| rest.split('#').next())
+        .unwrap_or(&endpoint);
+    let mm_device_id = HSTRING::from(mm_device_id);
+
+    unsafe {
+        let initialized = CoInitializeEx(None, COINIT_MULTITHREADED).is_ok();
+        let result = (|| {
+            let policy_config: IPolicyConfig = CoCreateInstance(&CLSID_POLICY_CONFIG, None, CLSCTX_ALL)?;
+            for role in [eConsole, eMultimedia, eCommunications] {
+                set_default_endpoint(&policy_config, PCWSTR(mm_device_id.as_ptr()), role).ok()?;
+            }
+            Ok(())
+        })();
+        if initialized {
+            CoUninitialize();
+        }
+        result
+    }
+}
@@ -0,0 +1,32 @@
+use windows::core::Result;
+use windows_registry::CURRENT_USER;
+
+const RUN_KEY: &str = r"Software\Microsoft\Windows\CurrentVersion\Run";
+const VALUE_NAME: &str = "BlueTray";
+
+// Whether Windows is set to launch this executable at login. An entry left behind by a
+// copy of the exe somewhere else doesn't count.
+pub fn is_enabled() -> bool {
+    let Ok(command) = launch_command() else {
+        return false;
+    };
+    CURRENT_USER
+        .open(RUN_KEY)
+        .and_then(|key| key.get_string(VALUE_NAME))
+        .is_ok_and(|value| value == command)
+}
+
+pub fn set_enabled(enabled: bool) -> Result<()> {
+    let key = CURRENT_USER.create(RUN_KEY)?;
+    if enabled {
+        key.set_string(VALUE_NAME, launch_command()?)
+    } else {
+        key.remove_value(VALUE_NAME)
+    }
+}
+
+// Quoted so a path with spaces isn't split into a program and arguments
+fn launch_command() -> Result<String> {
+    let exe = std::env::current_exe()?;
+    Ok(format!("\"{}\"", exe.display()))
+}
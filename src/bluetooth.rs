@@ -0,0 +1,764 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tracing::{debug, info, warn};
+use windows::core::{Error, IInspectable, Interface, GUID, HSTRING};
+use windows::Devices::Bluetooth::Rfcomm::RfcommDeviceService;
+use windows::Devices::Bluetooth::GenericAttributeProfile::{
+    GattCharacteristicUuids, GattCommunicationStatus, GattDeviceService, GattServiceUuids, GattSession,
+};
+use windows::Devices::Bluetooth::{
+    BluetoothAdapter, BluetoothCacheMode, BluetoothConnectionStatus, BluetoothDevice, BluetoothLEAppearanceCategories,
+    BluetoothLEDevice, BluetoothMajorClass,
+};
+use windows::Devices::Enumeration::{DeviceInformation, DeviceInformationKind, DeviceInformationUpdate, DeviceWatcher};
+use windows::Devices::Radios::{Radio, RadioAccessStatus, RadioState};
+use windows::Foundation::{IReference, TypedEventHandler};
+use windows::Networking::Sockets::StreamSocket;
+use windows::Storage::Streams::DataReader;
+use windows_collections::{IIterable, IMapView};
+
+use crate::connection::DeviceConnection;
+use crate::error::BlueTrayError;
+
+// How long to wait for an RFCOMM connect before giving up on the device
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+const SERVICE_DISCOVERY_ATTEMPTS: u32 = 3;
+const SERVICE_DISCOVERY_DELAY: Duration = Duration::from_millis(300);
+
+// How long one device enumeration gets before the stack counts as not responding
+pub const ENUMERATION_TIMEOUT: Duration = Duration::from_secs(15);
+
+// Windows flips the device to connected a moment after the socket opens, so give it a bit
+const LINK_CHECK_ATTEMPTS: u32 = 5;
+const LINK_CHECK_DELAY: Duration = Duration::from_millis(200);
+
+// Classic devices are reached over RFCOMM, BLE ones over GATT. There is no L2CAP path:
+// WinRT has no L2CAP socket API, and Winsock's AF_BTH only opens BTHPROTO_RFCOMM sockets,
+// so devices that need a raw L2CAP PSM can't be reached from user mode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceKind {
+    Classic,
+    LowEnergy,
+}
+
+// Coarse grouping for the menu, from the class of device (classic) or appearance (BLE)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceClass {
+    Audio,
+    Input,
+    Phone,
+    Other,
+}
+
+impl DeviceClass {
+    pub const ALL: [DeviceClass; 4] = [DeviceClass::Audio, DeviceClass::Input, DeviceClass::Phone, DeviceClass::Other];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            DeviceClass::Audio => "Audio",
+            DeviceClass::Input => "Input",
+            DeviceClass::Phone => "Phone",
+            DeviceClass::Other => "Other",
+        }
+    }
+}
+
+// An RFCOMM service a classic device offers, e.g. Hands-Free or Serial Port
+#[derive(Clone, Debug, PartialEq)]
+pub struct RfcommProfile {
+    pub uuid: GUID,
+    pub name: String,
+}
+
+// Short ids of the usual RFCOMM profiles, on the Bluetooth base UUID
+const PROFILE_NAMES: [(u32, &str); 9] = [
+    (0x1101, "Serial Port"),
+    (0x1105, "Object Push"),
+    (0x1106, "File Transfer"),
+    (0x1108, "Headset"),
+    (0x1112, "Headset Audio Gateway"),
+    (0x111E, "Hands-Free"),
+    (0x111F, "Hands-Free Audio Gateway"),
+    (0x112F, "Phonebook Access"),
+    (0x1132, "Message Access"),
+];
+
+impl RfcommProfile {
+    fn new(uuid: GUID) -> Self {
+        let base = GUID::from_u128(0x00000000_0000_1000_8000_00805F9B34FB);
+        let short = (uuid.data2 == base.data2 && uuid.data3 == base.data3 && uuid.data4 == base.data4)
+            .then_some(uuid.data1);
+        let name = PROFILE_NAMES
+            .iter()
+            .find(|(id, _)| Some(*id) == short)
+            .map_or_else(|| format!("{:?}", uuid), |(_, name)| name.to_string());
+        Self { uuid, name }
+    }
+}
+
+// A paired device together with what was learned about it at enumeration time
+pub struct PairedDevice {
+    pub device_id: HSTRING,
+    // What Windows calls the device, see device_display_name
+    pub name: String,
+    pub kind: DeviceKind,
+    pub class: DeviceClass,
+    pub address: Option<u64>,
+    pub connected: bool,
+    // False when there's nothing to open, e.g. no RFCOMM service on a classic device
+    pub connectable: bool,
+    // RFCOMM services of a classic device, in the order Windows lists them
+    pub profiles: Vec<RfcommProfile>,
+}
+
+// One attempt, over RFCOMM or GATT depending on the kind of device
+pub async fn connect_device(
+    device_id: &HSTRING,
+    kind: DeviceKind,
+    preferred_service: Option<GUID>,
+    timeout: Duration,
+) -> Result<DeviceConnection, BlueTrayError> {
+    match kind {
+        DeviceKind::Classic => connect_to_bluetooth_device(device_id, preferred_service, timeout)
+            .await
+            .map(DeviceConnection::Rfcomm),
+        DeviceKind::LowEnergy => connect_ble_device(device_id, timeout).await,
+    }
+}
+
+// Device ids tell classic and BLE devices apart, so try one and then the other
+pub async fn is_device_connected(device_id: &HSTRING) -> bool {
+    if let Ok(operation) = BluetoothDevice::FromIdAsync(device_id) {
+        if let Ok(device) = operation.await {
+            return device
+                .ConnectionStatus()
+                .is_ok_and(|status| status == BluetoothConnectionStatus::Connected);
+        }
+    }
+    let Ok(operation) = BluetoothLEDevice::FromIdAsync(device_id) else {
+        return false;
+    };
+    operation
+        .await
+        .and_then(|device| device.ConnectionStatus())
+        .is_ok_and(|status| status == BluetoothConnectionStatus::Connected)
+}
+
+// A Bluetooth adapter on this machine, for picking one when there are several
+#[derive(Clone, Debug)]
+pub struct AdapterInfo {
+    pub address: u64,
+    pub name: String,
+}
+
+pub async fn list_adapters() -> Result<Vec<AdapterInfo>, BlueTrayError> {
+    let infos = find_all(&BluetoothAdapter::GetDeviceSelector()?).await?;
+    let mut adapters = Vec::new();
+    for info in infos {
+        let adapter = BluetoothAdapter::FromIdAsync(&info.Id()?)?.await?;
+        adapters.push(AdapterInfo {
+            address: adapter.BluetoothAddress()?,
+            name: device_display_name(&info),
+        });
+    }
+    Ok(adapters)
+}
+
+async fn find_adapter(address: u64) -> Option<BluetoothAdapter> {
+    let infos = find_all(&BluetoothAdapter::GetDeviceSelector().ok()?).await.ok()?;
+    for info in infos {
+        let Ok(adapter_id) = info.Id() else {
+            continue;
+        };
+        let Ok(adapter) = BluetoothAdapter::FromIdAsync(&adapter_id).ok()?.await else {
+            continue;
+        };
+        if adapter.BluetoothAddress().is_ok_and(|found| found == address) {
+            return Some(adapter);
+        }
+    }
+    None
+}
+
+// The radio of the adapter with the given address, or of the default adapter. None when
+// there's no such adapter.
+pub async fn adapter_radio(address: Option<u64>) -> Option<Radio> {
+    let adapter = match address {
+        Some(address) => find_adapter(address).await?,
+        None => BluetoothAdapter::GetDefaultAsync().ok()?.await.ok()?,
+    };
+    adapter.GetRadioAsync().ok()?.await.ok()
+}
+
+// Device ids name the adapter a device is paired through, e.g.
+// "Bluetooth#Bluetooth00:1a:7d:da:71:13-aa:bb:cc:dd:ee:ff"
+fn is_on_adapter(device_id: &HSTRING, adapter: u64) -> bool {
+    let adapter = format!("{}-", format_address(adapter)).to_lowercase();
+    device_id.to_string().to_lowercase().contains(&adapter)
+}
+
+pub fn radio_is_on(radio: &Radio) -> bool {
+    radio.State().is_ok_and(|state| state == RadioState::On)
+}
+
+// Some systems lock radio control down by policy, which shows up as a denied access status
+pub async fn set_radio_state(radio: &Radio, on: bool) -> Result<(), BlueTrayError> {
+    let state = if on { RadioState::On } else { RadioState::Off };
+    match radio.SetStateAsync(state)?.await? {
+        RadioAccessStatus::Allowed => Ok(()),
+        _ => Err(BlueTrayError::RadioAccessDenied),
+    }
+}
+
+// Calls back with the new on/off state whenever the radio is toggled. Windows may fire
+// this more than once for the same state.
+pub fn watch_radio<F>(radio: &Radio, on_change: F) -> Result<i64, Error>
+where
+    F: Fn(bool) + Send + 'static,
+{
+    radio.StateChanged(&TypedEventHandler::<Radio, IInspectable>::new(move |radio, _| {
+        if let Ok(radio) = radio.ok() {
+            on_change(radio_is_on(radio));
+        }
+        Ok(())
+    }))
+}
+
+// What a `DeviceWatcher` saw change in the set of paired devices
+pub enum DeviceChange {
+    Added,
+    Removed(HSTRING),
+    // The device was in range or connected just now
+    Seen(HSTRING),
+}
+
+// Presence properties Windows keeps on a device's association endpoint
+const IS_PRESENT_PROPERTY: &str = "System.Devices.Aep.IsPresent";
+const IS_CONNECTED_PROPERTY: &str = "System.Devices.Aep.IsConnected";
+
+fn is_seen(properties: Result<IMapView<HSTRING, IInspectable>, Error>) -> bool {
+    let Ok(properties) = properties else {
+        return false;
+    };
+    [IS_PRESENT_PROPERTY, IS_CONNECTED_PROPERTY].iter().any(|key| {
+        properties
+            .Lookup(&HSTRING::from(*key))
+            .and_then(|value| value.cast::<IReference<bool>>()?.Value())
+            .unwrap_or(false)
+    })
+}
+
+// FindAllAsync with a deadline, since a flaky stack can leave the call hanging. It can also
+// succeed without handing back a collection, which is taken as nothing found rather than
+// as a failure, so the menu says there are no paired devices.
+async fn find_all(selector: &HSTRING) -> Result<Vec<DeviceInformation>, BlueTrayError> {
+    let operation = DeviceInformation::FindAllAsyncAqsFilter(selector)?;
+    match tokio::time::timeout(ENUMERATION_TIMEOUT, operation).await {
+        Ok(Ok(infos)) => Ok(infos.into_iter().collect()),
+        // A null collection comes back as an error without a failure code
+        Ok(Err(e)) if e.code().is_ok() => {
+            warn!("Device enumeration came back without a list, taking it as no devices");
+            Ok(Vec::new())
+        }
+        Ok(Err(e)) => Err(e.into()),
+        Err(_) => {
+            warn!(timeout = ?ENUMERATION_TIMEOUT, "Device enumeration did not finish");
+            Err(BlueTrayError::NotResponding(ENUMERATION_TIMEOUT))
+        }
+    }
+}
+
+fn paired_device_selector(kind: DeviceKind) -> Result<HSTRING, Error> {
+    match kind {
+        DeviceKind::Classic => BluetoothDevice::GetDeviceSelectorFromPairingState(true),
+        DeviceKind::LowEnergy => BluetoothLEDevice::GetDeviceSelectorFromPairingState(true),
+    }
+}
+
+// Starts watchers for paired classic and BLE devices. The watchers stop firing once
+// dropped, so the caller has to keep them around.
+pub fn watch_paired_devices<F>(on_change: F) -> Result<Vec<DeviceWatcher>, Error>
+where
+    F: Fn(DeviceChange) + Send + Sync + 'static,
+{
+    let on_change = Arc::new(on_change);
+    let mut watchers = Vec::new();
+    for kind in [DeviceKind::Classic, DeviceKind::LowEnergy] {
+        let properties = IIterable::<HSTRING>::from(vec![
+            HSTRING::from(IS_PRESENT_PROPERTY),
+            HSTRING::from(IS_CONNECTED_PROPERTY),
+        ]);
+        let watcher = DeviceInformation::CreateWatcherAqsFilterAndAdditionalProperties(
+            &paired_device_selector(kind)?,
+            &properties,
+        )?;
+
+        // The watcher replays every known device as Added before EnumerationCompleted;
+        // those are already in the menu
+        let enumerated = Arc::new(AtomicBool::new(false));
+
+        let added = on_change.clone();
+        let added_enumerated = enumerated.clone();
+        watcher.Added(&TypedEventHandler::<DeviceWatcher, DeviceInformation>::new(move |_, info| {
+            if added_enumerated.load(Ordering::SeqCst) {
+                added(DeviceChange::Added);
+            }
+            if let Ok(info) = info.ok() {
+                if is_seen(info.Properties()) {
+                    added(DeviceChange::Seen(info.Id()?));
+                }
+            }
+            Ok(())
+        }))?;
+
+        let removed = on_change.clone();
+        watcher.Removed(&TypedEventHandler::<DeviceWatcher, DeviceInformationUpdate>::new(
+            move |_, update| {
+                if let Ok(update) = update.ok() {
+                    removed(DeviceChange::Removed(update.Id()?));
+                }
+                Ok(())
+            },
+        ))?;
+
+        // Windows only delivers Added events when Updated has a handler too. Updates carry
+        // the presence properties, for the last seen times.
+        let updated = on_change.clone();
+        watcher.Updated(&TypedEventHandler::<DeviceWatcher, DeviceInformationUpdate>::new(
+            move |_, update| {
+                if let Ok(update) = update.ok() {
+                    if is_seen(update.Properties()) {
+                        updated(DeviceChange::Seen(update.Id()?));
+                    }
+                }
+                Ok(())
+            },
+        ))?;
+
+        watcher.EnumerationCompleted(&TypedEventHandler::<DeviceWatcher, IInspectable>::new(move |_, _| {
+            enumerated.store(true, Ordering::SeqCst);
+            Ok(())
+        }))?;
+
+        watcher.Start()?;
+        watchers.push(watcher);
+    }
+
+    Ok(watchers)
+}
+
+// Only the devices paired through the given adapter, when there is one
+pub async fn get_paired_bluetooth_devices(adapter: Option<u64>) -> Result<Vec<PairedDevice>, BlueTrayError> {
+    // An adapter that was unplugged shouldn't leave the menu empty
+    let adapter = match adapter {
+        Some(address) if find_adapter(address).await.is_none() => {
+            warn!(adapter = %format_address(address), "Configured Bluetooth adapter not found, using all devices");
+            None
+        }
+        adapter => adapter,
+    };
+
+    // Enumeration still lists paired devices with the radio off, but none are reachable
+    if let Some(radio) = adapter_radio(adapter).await {
+        if !radio_is_on(&radio) {
+            return Err(BlueTrayError::RadioOff);
+        }
+    }
+
+    let mut devices = Vec::new();
+    for kind in [DeviceKind::Classic, DeviceKind::LowEnergy] {
+        let device_infos = find_all(&paired_device_selector(kind)?).await?;
+
+        for info in device_infos {
+            // Nothing can be done with a device that has no id
+            let Ok(device_id) = info.Id() else {
+                continue;
+            };
+            if adapter.is_some_and(|adapter| !is_on_adapter(&device_id, adapter)) {
+                continue;
+            }
+            let device = probe_paired_device(device_id, device_display_name(&info), kind).await;
+            debug!(
+                device_id = %device.device_id,
+                ?kind,
+                class = ?device.class,
+                connected = device.connected,
+                connectable = device.connectable,
+                "Enumerated paired device"
+            );
+
+            // Dual-mode devices show up in both lists; the classic entry is the one to keep
+            let duplicate = kind == DeviceKind::LowEnergy
+                && device.address.is_some()
+                && devices.iter().any(|known: &PairedDevice| known.address == device.address);
+            if !duplicate {
+                devices.push(device);
+            }
+        }
+    }
+
+    Ok(devices)
+}
+
+// The usual colon-separated form, e.g. 00:1A:7D:DA:71:13
+pub fn format_address(address: u64) -> String {
+    (0..6)
+        .rev()
+        .map(|byte| format!("{:02X}", (address >> (byte * 8)) & 0xff))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+// The reverse of format_address; None for anything that isn't six hex bytes
+pub fn parse_address(text: &str) -> Option<u64> {
+    let bytes: Vec<&str> = text.trim().split(':').collect();
+    if bytes.len() != 6 {
+        return None;
+    }
+    bytes.iter().try_fold(0u64, |address, byte| {
+        let byte = u8::from_str_radix(byte, 16).ok()?;
+        Some(address << 8 | byte as u64)
+    })
+}
+
+// Some devices report an empty name, so fall back to the id rather than showing a blank item
+fn device_display_name(info: &DeviceInformation) -> String {
+    if let Ok(name) = info.Name() {
+        if !name.is_empty() {
+            return name.to_string();
+        }
+    }
+    match info.Id() {
+        Ok(device_id) if !device_id.is_empty() => device_id.to_string(),
+        _ => "Unknown device".to_string(),
+    }
+}
+
+async fn probe_paired_device(device_id: HSTRING, name: String, kind: DeviceKind) -> PairedDevice {
+    let mut device = PairedDevice {
+        device_id: device_id.clone(),
+        name,
+        kind,
+        class: DeviceClass::Other,
+        address: None,
+        connected: false,
+        connectable: false,
+        profiles: Vec::new(),
+    };
+
+    // The cached service lists are enough to tell whether there's anything to connect to
+    match kind {
+        DeviceKind::Classic => {
+            let Ok(operation) = BluetoothDevice::FromIdAsync(&device_id) else {
+                return device;
+            };
+            let Ok(bluetooth_device) = operation.await else {
+                return device;
+            };
+            device.address = bluetooth_device.BluetoothAddress().ok();
+            device.class = match bluetooth_device.ClassOfDevice().and_then(|class| class.MajorClass()) {
+                Ok(BluetoothMajorClass::AudioVideo) => DeviceClass::Audio,
+                Ok(BluetoothMajorClass::Peripheral) => DeviceClass::Input,
+                Ok(BluetoothMajorClass::Phone) => DeviceClass::Phone,
+                _ => DeviceClass::Other,
+            };
+            device.connected = bluetooth_device
+                .ConnectionStatus()
+                .is_ok_and(|status| status == BluetoothConnectionStatus::Connected);
+            if let Ok(operation) = bluetooth_device.GetRfcommServicesWithCacheModeAsync(BluetoothCacheMode::Cached) {
+                if let Ok(services) = operation.await.and_then(|result| result.Services()) {
+                    device.profiles = services
+                        .into_iter()
+                        .filter_map(|service| service.ServiceId().and_then(|id| id.Uuid()).ok())
+                        .map(RfcommProfile::new)
+                        .collect();
+                }
+                device.connectable = !device.profiles.is_empty();
+            }
+        }
+        DeviceKind::LowEnergy => {
+            let Ok(operation) = BluetoothLEDevice::FromIdAsync(&device_id) else {
+                return device;
+            };
+            let Ok(le_device) = operation.await else {
+                return device;
+            };
+            device.address = le_device.BluetoothAddress().ok();
+            device.class = le_device
+                .Appearance()
+                .and_then(|appearance| appearance.Category())
+                .map_or(DeviceClass::Other, le_device_class);
+            device.connected = le_device
+                .ConnectionStatus()
+                .is_ok_and(|status| status == BluetoothConnectionStatus::Connected);
+            if let Ok(operation) = le_device.GetGattServicesWithCacheModeAsync(BluetoothCacheMode::Cached) {
+                device.connectable = operation
+                    .await
+                    .and_then(|result| result.Services())
+                    .is_ok_and(|services| services.Size().unwrap_or(0) > 0);
+            }
+            let _ = le_device.Close();
+        }
+    }
+
+    device
+}
+
+// BLE appearance categories don't line up with the classic major classes; map the ones
+// that clearly mean audio, input or a phone and leave the rest as Other
+fn le_device_class(category: u16) -> DeviceClass {
+    let is = |expected: Result<u16, Error>| expected.is_ok_and(|expected| expected == category);
+    if is(BluetoothLEAppearanceCategories::MediaPlayer()) {
+        DeviceClass::Audio
+    } else if is(BluetoothLEAppearanceCategories::HumanInterfaceDevice())
+        || is(BluetoothLEAppearanceCategories::RemoteControl())
+        || is(BluetoothLEAppearanceCategories::BarcodeScanner())
+    {
+        DeviceClass::Input
+    } else if is(BluetoothLEAppearanceCategories::Phone()) {
+        DeviceClass::Phone
+    } else {
+        DeviceClass::Other
+    }
+}
+
+// The preferred service if the device offers it, otherwise the first one
+fn select_rfcomm_service(
+    device_id: &HSTRING,
+    mut services: Vec<RfcommDeviceService>,
+    preferred_service: Option<GUID>,
+) -> Result<RfcommDeviceService, BlueTrayError> {
+    if services.is_empty() {
+        return Err(BlueTrayError::NoRfcommService(device_id.to_string()));
+    }
+
+    for service in &services {
+        if let Ok(uuid) = service.ServiceId().and_then(|id| id.Uuid()) {
+            debug!(%device_id, ?uuid, "Found RFCOMM service");
+        }
+    }
+
+    if let Some(wanted) = preferred_service {
+        let position = services
+            .iter()
+            .position(|service| service.ServiceId().and_then(|id| id.Uuid()).is_ok_and(|uuid| uuid == wanted));
+        match position {
+            Some(position) => return Ok(services.swap_remove(position)),
+            None => warn!(%device_id, ?wanted, "Preferred RFCOMM service not offered, using the first one"),
+        }
+    }
+    Ok(services.swap_remove(0))
+}
+
+// Right after FromIdAsync the service list sometimes comes back empty, and fills in a
+// moment later; ask again a few times before deciding the device has none
+async fn discover_rfcomm_services(
+    device_id: &HSTRING,
+    device: &BluetoothDevice,
+) -> Result<Vec<RfcommDeviceService>, BlueTrayError> {
+    let mut attempt = 1;
+    loop {
+        let services: Vec<_> = device.GetRfcommServicesAsync()?.await?.Services()?.into_iter().collect();
+        if !services.is_empty() {
+            if attempt > 1 {
+                info!(%device_id, attempt, "RFCOMM services showed up after retrying discovery");
+            }
+            return Ok(services);
+        }
+        if attempt == SERVICE_DISCOVERY_ATTEMPTS {
+            warn!(%device_id, attempts = attempt, "RFCOMM service discovery stayed empty");
+            return Ok(services);
+        }
+        debug!(%device_id, attempt, "RFCOMM service discovery came back empty, retrying");
+        tokio::time::sleep(SERVICE_DISCOVERY_DELAY).await;
+        attempt += 1;
+    }
+}
+
+// Connects to the RFCOMM service with the given UUID, or the first service the device offers
+pub async fn connect_to_bluetooth_device(
+    device_id: &HSTRING,
+    preferred_service: Option<GUID>,
+    timeout: Duration,
+) -> Result<StreamSocket, BlueTrayError> {
+    info!(%device_id, "Connecting to classic device");
+    let device = match BluetoothDevice::FromIdAsync(device_id)?.await {
+        Ok(device) => device,
+        // A null device comes back as an error without a failure code
+        Err(e) if e.code().is_ok() => {
+            return Err(BlueTrayError::DeviceNotFound(device_id.to_string()))
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let services = discover_rfcomm_services(device_id, &device).await?;
+    let service = select_rfcomm_service(device_id, services, preferred_service)?;
+    let socket = StreamSocket::new()?;
+    debug!(
+        %device_id,
+        host = %service.ConnectionHostName()?.ToString()?,
+        service = %service.ConnectionServiceName()?,
+        "Opening RFCOMM socket"
+    );
+    let connect = socket.ConnectAsync(
+        &service.ConnectionHostName()?,
+        &service.ConnectionServiceName()?)?;
+    match tokio::time::timeout(timeout, connect).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => {
+            let _ = socket.Close();
+            return Err(e.into());
+        }
+        Err(_) => {
+            // Closing the socket also cancels the pending connect
+            let _ = socket.Close();
+            return Err(BlueTrayError::ConnectTimeout(timeout));
+        }
+    }
+
+    // The connect can complete without a usable channel behind it; don't hand out a dead socket
+    if !link_established(&device, &socket).await {
+        let _ = socket.Close();
+        return Err(BlueTrayError::LinkNotEstablished(device_id.to_string()));
+    }
+    info!(%device_id, name = %device.Name()?, "Connected to classic device");
+
+    Ok(socket)
+}
+
+// Whether a freshly opened socket has a remote end and the device shows up as connected
+async fn link_established(device: &BluetoothDevice, socket: &StreamSocket) -> bool {
+    let has_remote = socket
+        .Information()
+        .and_then(|information| information.RemoteHostName())
+        .is_ok();
+    if !has_remote {
+        debug!("RFCOMM socket has no remote host");
+        return false;
+    }
+    for attempt in 1..=LINK_CHECK_ATTEMPTS {
+        let connected = device
+            .ConnectionStatus()
+            .is_ok_and(|status| status == BluetoothConnectionStatus::Connected);
+        if connected {
+            return true;
+        }
+        if attempt < LINK_CHECK_ATTEMPTS {
+            tokio::time::sleep(LINK_CHECK_DELAY).await;
+        }
+    }
+    debug!("Device never reported connected after the RFCOMM socket opened");
+    false
+}
+
+// Opens a GATT session and asks Windows to keep the link up while it's held
+async fn connect_ble_device(device_id: &HSTRING, timeout: Duration) -> Result<DeviceConnection, BlueTrayError> {
+    info!(%device_id, "Connecting to BLE device");
+    let device = match BluetoothLEDevice::FromIdAsync(device_id)?.await {
+        Ok(device) => device,
+        // A null device comes back as an error without a failure code
+        Err(e) if e.code().is_ok() => {
+            return Err(BlueTrayError::DeviceNotFound(device_id.to_string()))
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let session = GattSession::FromDeviceIdAsync(&device.BluetoothDeviceId()?)?.await?;
+    if session.CanMaintainConnection()? {
+        session.SetMaintainConnection(true)?;
+    }
+
+    // Opening the services is what actually brings the link up
+    let services = match tokio::time::timeout(timeout, device.GetGattServicesAsync()?).await {
+        Ok(result) => result,
+        Err(_) => {
+            let _ = session.Close();
+            let _ = device.Close();
+            return Err(BlueTrayError::ConnectTimeout(timeout));
+        }
+    };
+    let reachable = services
+        .as_ref()
+        .is_ok_and(|services| {
+            services.Status().is_ok_and(|status| status == GattCommunicationStatus::Success)
+                && services.Services().is_ok_and(|services| services.Size().unwrap_or(0) > 0)
+        });
+    if !reachable {
+        let _ = session.Close();
+        let _ = device.Close();
+        return Err(BlueTrayError::NoGattService(device_id.to_string()));
+    }
+    info!(%device_id, name = %device.Name()?, "Connected to BLE device");
+
+    Ok(DeviceConnection::Gatt { device, session })
+}
+
+// Battery percentage from the GATT Battery Service. Classic devices usually expose it over
+// their LE side, so look those up by address.
+pub async fn read_battery(device_id: &HSTRING) -> Option<u8> {
+    let le_device = match BluetoothLEDevice::FromIdAsync(device_id).ok()?.await {
+        Ok(le_device) => le_device,
+        Err(_) => {
+            let device = BluetoothDevice::FromIdAsync(device_id).ok()?.await.ok()?;
+            let address = device.BluetoothAddress().ok()?;
+            BluetoothLEDevice::FromBluetoothAddressAsync(address).ok()?.await.ok()?
+        }
+    };
+
+    let level = battery_service_level(&le_device).await;
+    // Closed however the read went, rather than left open until it's dropped
+    let _ = le_device.Close();
+    level.filter(|level| *level <= 100)
+}
+
+async fn battery_service_level(le_device: &BluetoothLEDevice) -> Option<u8> {
+    let services = le_device
+        .GetGattServicesForUuidAsync(GattServiceUuids::Battery().ok()?)
+        .ok()?
+        .await
+        .ok()?;
+    if services.Status().ok()? != GattCommunicationStatus::Success {
+        return None;
+    }
+    let service = services.Services().ok()?.GetAt(0).ok()?;
+    let level = battery_level(&service).await;
+    let _ = service.Close();
+    level
+}
+
+async fn battery_level(service: &GattDeviceService) -> Option<u8> {
+    let characteristics = service
+        .GetCharacteristicsForUuidAsync(GattCharacteristicUuids::BatteryLevel().ok()?)
+        .ok()?
+        .await
+        .ok()?;
+    let characteristic = characteristics.Characteristics().ok()?.GetAt(0).ok()?;
+    let read = characteristic.ReadValueAsync().ok()?.await.ok()?;
+    if read.Status().ok()? != GattCommunicationStatus::Success {
+        return None;
+    }
+    let reader = DataReader::FromBuffer(&read.Value().ok()?).ok()?;
+    reader.ReadByte().ok()
+}
+
+const SIGNAL_STRENGTH_PROPERTY: &str = "System.Devices.Aep.SignalStrength";
+
+// Windows keeps the last signal strength it saw on the device's association endpoint. BLE
+// devices report it; classic ones mostly don't, and get None.
+pub async fn read_rssi(device_id: &HSTRING) -> Option<i16> {
+    let property = HSTRING::from(SIGNAL_STRENGTH_PROPERTY);
+    let operation = {
+        let properties = IIterable::<HSTRING>::from(vec![property.clone()]);
+        DeviceInformation::CreateFromIdAsyncWithKindAndAdditionalProperties(
+            device_id,
+            &properties,
+            DeviceInformationKind::AssociationEndpoint,
+        )
+        .ok()?
+    };
+    let info = operation.await.ok()?;
+    let value = info.Properties().ok()?.Lookup(&property).ok()?;
+    let rssi = value.cast::<IReference<i32>>().ok()?.Value().ok()?;
+    i16::try_from(rssi).ok()
+}
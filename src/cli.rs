@@ -0,0 +1,332 @@
+use std::collections::HashSet;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+
+use tokio::sync::Notify;
+use windows::Win32::System::Console::{AttachConsole, ATTACH_PARENT_PROCESS};
+
+use crate::bluetooth::{format_address, PairedDevice};
+use crate::config::Config;
+use crate::connection::{connect_with_retry, get_by_device_id, normalize_device_id, same_device_id};
+use crate::ipc::{self, format_listing, kind_label, DeviceListing, ListingFormat, Request, Response};
+use crate::menu::preferred_service;
+use crate::platform::{BluetoothPlatform, WindowsPlatform};
+
+const USAGE: &str = "\
+Usage: bluetray [--connect <device> | --disconnect <device> | --list [--json] | --refresh | --daemon |
+                 --export-config <file> | --import-config <file> [--replace]]
+
+  --connect <device>     Connect to a paired device, by name or id
+  --disconnect <device>  Disconnect a device the running tray connected; without
+                         one running, check that the device is disconnected
+  --list                 Print the paired devices, as a table on a terminal and
+                         one JSON object per line otherwise
+  --list --json          Print the paired devices as a single JSON array
+  --refresh              Have the running tray reload its device list
+  --daemon, --no-tray    Run without the tray icon, keeping the devices connected
+                         last time connected until stopped with Ctrl+C
+  --export-config <file> Write the current settings to a file
+  --import-config <file> Add the device settings in a file written by --export-config
+                         to the current ones, warning about devices that aren't paired
+                         here; with --replace, use everything in it instead
+
+Commands go to the running tray when there is one, so connections it makes stay
+up. Without arguments bluetray runs in the tray.";
+
+// Exit codes for scripts
+const EXIT_OK: i32 = 0;
+const EXIT_FAILED: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+
+enum Command {
+    Connect(String),
+    Disconnect(String),
+    List { array: bool },
+    Refresh,
+    ExportConfig(PathBuf),
+    ImportConfig { path: PathBuf, replace: bool },
+}
+
+// Runs a command given on the command line and returns the exit code, or None when there
+// is none and the tray should start as usual
+pub async fn run() -> Option<i32> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.is_empty() || daemon_requested() {
+        return None;
+    }
+
+    attach_console();
+    let command = match parse(&args) {
+        Ok(command) => command,
+        Err(message) => {
+            eprintln!("{}\n\n{}", message, USAGE);
+            return Some(EXIT_USAGE);
+        }
+    };
+    // Unlike the tray, a command doesn't go on with the defaults, which could e.g. pick
+    // the wrong adapter or overwrite the file on import
+    let config = match Config::reload() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Could not read the settings: {}", e);
+            return Some(EXIT_FAILED);
+        }
+    };
+    let config = &config;
+
+    // Settings are only files, so these never need the running tray, which notices the
+    // config file changing on its own
+    let request = match &command {
+        Command::ExportConfig(path) => return Some(export_config(config, path)),
+        Command::ImportConfig { path, replace } => return Some(import_config(config, path, *replace).await),
+        Command::Connect(device) => Request::Connect { device: device.clone() },
+        Command::Disconnect(device) => Request::Disconnect { device: device.clone() },
+        Command::List { .. } => Request::List,
+        Command::Refresh => Request::Refresh,
+    };
+    if let Some(response) = ipc::send(&request).await {
+        let array = matches!(command, Command::List { array: true });
+        return Some(print_response(response, array));
+    }
+
+    let platform = WindowsPlatform::from_config(config);
+    let devices = match platform.list_paired_devices().await {
+        Ok(devices) => devices,
+        Err(e) => {
+            eprintln!("Could not list Bluetooth devices: {}", e);
+            return Some(EXIT_FAILED);
+        }
+    };
+
+    Some(match command {
+        Command::List { array } => list(&platform, config, &devices, array).await,
+        Command::Refresh => {
+            eprintln!("bluetray is not running, there is nothing to refresh");
+            EXIT_FAILED
+        }
+        Command::ExportConfig(_) | Command::ImportConfig { .. } => unreachable!("handled before the pipe"),
+        Command::Connect(wanted) => {
+            let Some(device) = find(config, &devices, &wanted) else {
+                eprintln!("No paired device called {}", wanted);
+                return Some(EXIT_FAILED);
+            };
+            let preferred = preferred_service(&config.connect_profiles, &device.device_id);
+            let stop = Notify::new();
+            let result = connect_with_retry(
+                &platform,
+                &device.device_id,
+                device.kind,
+                preferred,
+                config.retry,
+                &stop,
+                |_, _| {},
+            );
+            match result.await {
+                // The link closes when this process exits, but the device stays connected
+                // to Windows for the profiles it set up
+                Ok((_, elapsed)) => {
+                    println!("Connected to {} in {:.1}s", display_name(config, device), elapsed.as_secs_f32());
+                    EXIT_OK
+                }
+                Err(e) => {
+                    eprintln!("Could not connect to {}: {}", display_name(config, device), e);
+                    EXIT_FAILED
+                }
+            }
+        }
+        // Windows has no call to drop another app's link, so all this can do is report
+        Command::Disconnect(wanted) => {
+            let Some(device) = find(config, &devices, &wanted) else {
+                eprintln!("No paired device called {}", wanted);
+                return Some(EXIT_FAILED);
+            };
+            if platform.is_connected(&device.device_id).await {
+                eprintln!(
+                    "{} is still connected; disconnect it from the running tray or the app using it",
+                    display_name(config, device)
+                );
+                EXIT_FAILED
+            } else {
+                println!("{} is not connected", display_name(config, device));
+                EXIT_OK
+            }
+        }
+    })
+}
+
+// --daemon runs the app itself rather than a one-off command, so main checks for it
+// after logging is set up
+pub fn daemon_requested() -> bool {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    matches!(args.as_slice(), [flag] if flag == "--daemon" || flag == "--no-tray")
+}
+
+// The binary has no console of its own, so write to the one it was started from
+pub fn attach_console() {
+    unsafe {
+        let _ = AttachConsole(ATTACH_PARENT_PROCESS);
+    }
+}
+
+fn parse(args: &[String]) -> Result<Command, String> {
+    let mut args = args.iter();
+    let command = match args.next().map(String::as_str) {
+        Some("--connect") => Command::Connect(args.next().ok_or("--connect needs a device")?.clone()),
+        Some("--disconnect") => Command::Disconnect(args.next().ok_or("--disconnect needs a device")?.clone()),
+        Some("--list") => match args.next().map(String::as_str) {
+            Some("--json") => Command::List { array: true },
+            Some(other) => return Err(format!("Unexpected argument {}", other)),
+            None => Command::List { array: false },
+        },
+        Some("--refresh") => Command::Refresh,
+        Some("--export-config") => Command::ExportConfig(args.next().ok_or("--export-config needs a file")?.into()),
+        Some("--import-config") => {
+            let path = args.next().ok_or("--import-config needs a file")?.into();
+            match args.next().map(String::as_str) {
+                Some("--replace") => Command::ImportConfig { path, replace: true },
+                Some(other) => return Err(format!("Unexpected argument {}", other)),
+                None => Command::ImportConfig { path, replace: false },
+            }
+        }
+        Some(other) => return Err(format!("Unknown argument {}", other)),
+        None => unreachable!("checked by the caller"),
+    };
+    match args.next() {
+        Some(extra) => Err(format!("Unexpected argument {}", extra)),
+        None => Ok(command),
+    }
+}
+
+fn export_config(config: &Config, path: &Path) -> i32 {
+    match config.export(path) {
+        Ok(()) => {
+            println!("Settings written to {}", path.display());
+            EXIT_OK
+        }
+        Err(e) => {
+            eprintln!("Could not write {}: {}", path.display(), e);
+            EXIT_FAILED
+        }
+    }
+}
+
+async fn import_config(config: &Config, path: &Path, replace: bool) -> i32 {
+    let imported = match Config::import(path) {
+        Ok(imported) => imported,
+        Err(e) => {
+            eprintln!("Could not read {}: {}", path.display(), e);
+            return EXIT_FAILED;
+        }
+    };
+
+    // Ids name the adapter as well as the device, so on another machine even the same
+    // device usually has a different one. Their settings are imported anyway, in case
+    // it gets paired later.
+    let platform = WindowsPlatform::from_config(config);
+    match platform.list_paired_devices().await {
+        Ok(devices) => {
+            let paired: HashSet<String> = devices
+                .iter()
+                .map(|device| normalize_device_id(&device.device_id.to_string()))
+                .collect();
+            for device_id in imported.device_ids() {
+                if !paired.contains(&normalize_device_id(&device_id)) {
+                    match get_by_device_id(&imported.aliases, &device_id) {
+                        Some(alias) => eprintln!("Warning: {} ({}) is not paired with this machine", alias, device_id),
+                        None => eprintln!("Warning: {} is not paired with this machine", device_id),
+                    }
+                }
+            }
+        }
+        Err(e) => eprintln!("Warning: could not list Bluetooth devices to check the imported ones: {}", e),
+    }
+
+    let updated = if replace {
+        imported
+    } else {
+        let mut merged = config.clone();
+        merged.merge(imported);
+        merged
+    };
+    match updated.try_save() {
+        Ok(()) => {
+            let how = if replace { "replacing the current settings" } else { "merged into the current settings" };
+            println!("Imported {}, {}", path.display(), how);
+            EXIT_OK
+        }
+        Err(e) => {
+            eprintln!("Could not save the imported settings: {}", e);
+            EXIT_FAILED
+        }
+    }
+}
+
+// By id, then by name or alias ignoring case
+fn find<'a>(config: &Config, devices: &'a [PairedDevice], wanted: &str) -> Option<&'a PairedDevice> {
+    devices.iter().find(|device| same_device_id(&device.device_id.to_string(), wanted)).or_else(|| {
+        devices
+            .iter()
+            .find(|device| device.name.eq_ignore_ascii_case(wanted) || display_name(config, device).eq_ignore_ascii_case(wanted))
+    })
+}
+
+fn display_name(config: &Config, device: &PairedDevice) -> String {
+    match get_by_device_id(&config.aliases, &device.device_id.to_string()) {
+        Some(alias) if !alias.is_empty() => alias.clone(),
+        _ => device.name.clone(),
+    }
+}
+
+async fn list(platform: &WindowsPlatform, config: &Config, devices: &[PairedDevice], array: bool) -> i32 {
+    let mut listing = Vec::with_capacity(devices.len());
+    for device in devices {
+        listing.push(DeviceListing {
+            id: device.device_id.to_string(),
+            name: display_name(config, device),
+            kind: kind_label(device.kind).to_string(),
+            class: device.class.label().to_string(),
+            address: device.address.map(format_address),
+            connected: device.connected,
+            connectable: device.connectable,
+            battery: platform.battery(&device.device_id).await,
+        });
+    }
+    print_listing(&listing, array)
+}
+
+// An answer from the running tray, printed the same way as when the command runs here
+fn print_response(response: Response, array: bool) -> i32 {
+    if !response.ok {
+        eprintln!("{}", response.message.unwrap_or_default());
+        return EXIT_FAILED;
+    }
+    match response.devices {
+        Some(devices) => print_listing(&devices, array),
+        None => {
+            println!("{}", response.message.unwrap_or_default());
+            EXIT_OK
+        }
+    }
+}
+
+fn print_listing(listing: &[DeviceListing], array: bool) -> i32 {
+    // A terminal gets something readable unless JSON was asked for explicitly
+    if !array && std::io::stdout().is_terminal() {
+        print!("{}", format_listing(listing, ListingFormat::Table));
+        return EXIT_OK;
+    }
+    let result = if array {
+        serde_json::to_string_pretty(listing).map(|json| println!("{}", json))
+    } else {
+        listing
+            .iter()
+            .try_for_each(|device| serde_json::to_string(device).map(|json| println!("{}", json)))
+    };
+    match result {
+        Ok(()) => EXIT_OK,
+        Err(e) => {
+            eprintln!("Could not write the device list: {}", e);
+            EXIT_FAILED
+        }
+    }
+}
@@ -0,0 +1,10 @@
+use windows::core::{Error, HSTRING};
+use windows::ApplicationModel::DataTransfer::{Clipboard, DataPackage};
+
+// Puts plain text on the clipboard. Flushing keeps it there after bluetray exits.
+pub fn copy_text(text: &str) -> Result<(), Error> {
+    let package = DataPackage::new()?;
+    package.SetText(&HSTRING::from(text))?;
+    Clipboard::SetContent(&package)?;
+    Clipboard::Flush()
+}
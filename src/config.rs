@@ -0,0 +1,359 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::bluetooth::{parse_address, DEFAULT_CONNECT_TIMEOUT};
+use crate::connection::{contains_device_id, same_device_id, DEFAULT_MAX_CONCURRENT_CONNECTS};
+use crate::state::app_data_dir;
+
+// User settings, stored in %APPDATA%/bluetray/config.toml. Anything missing from the file
+// takes its default, so a partial file is fine.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    // Reconnect the devices that were connected when bluetray last exited
+    pub auto_reconnect: bool,
+    // Devices that are reconnected right away when their connection drops, by id
+    pub reconnect_when_lost: Vec<String>,
+    // After waking from sleep, reconnect the devices that were connected before it
+    pub reconnect_on_resume: bool,
+    // Devices connected as soon as they come into range, by id, e.g. a headset when you
+    // walk in the door
+    pub auto_connect_devices: Vec<String>,
+    // A device that keeps dropping out of range and coming back is connected on its return
+    // at most once per this many seconds
+    pub auto_connect_cooldown_secs: u64,
+    // Connect failures in a row after which a device is marked with ⚠ in the menu, as a
+    // hint that it may need re-pairing; 0 turns the mark off
+    pub flaky_after_failures: u32,
+    // Leave marked devices out of automatic reconnects until they connect again
+    pub pause_reconnect_when_flaky: bool,
+    pub notifications: bool,
+    // Per attempt; at least 2 seconds
+    pub connect_timeout_secs: u64,
+    // How often connected devices are checked for having dropped, and their battery and
+    // signal refreshed. Shorter notices a lost device sooner, but every check wakes the
+    // radio and each device, which costs battery on both ends. At least 5 seconds.
+    pub health_check_interval_secs: u64,
+    pub retry: RetryPolicy,
+    // Connect attempts allowed to run at once; the rest wait their turn, e.g. when several
+    // devices are reconnected on startup
+    pub max_concurrent_connects: usize,
+    // Friendly names shown instead of the device's own, keyed by device id
+    pub aliases: HashMap<String, String>,
+    // RFCOMM service to connect to, as a UUID keyed by device id, e.g. Hands-Free on a
+    // headset that also offers a headset profile. A "*" entry is used for devices without
+    // their own, e.g. the Serial Port UUID. Devices that don't offer the service, and
+    // those with no entry at all, get their first service.
+    pub connect_profiles: HashMap<String, String>,
+    pub device_order: DeviceOrder,
+    // Puts devices into Audio / Input / Phone / Other submenus instead of one flat list
+    pub group_by_class: bool,
+    // Moves connected devices to the top of their section, re-sorting as they connect and
+    // disconnect; off keeps every device in one place
+    pub connected_first: bool,
+    // Address of the Bluetooth adapter to use, e.g. "00:1A:7D:DA:71:13", for machines with
+    // a built-in radio and a dongle. The system default when unset.
+    pub adapter: Option<String>,
+    // Also write logs to %APPDATA%/bluetray/logs, since a tray app has no console
+    pub log_to_file: bool,
+    // Device the global hotkey connects or disconnects
+    pub favorite_device: Option<String>,
+    // Device a middle click on the tray icon connects or disconnects. Falls back to the
+    // favorite device, and to opening the menu when neither is set.
+    pub quick_toggle_device: Option<String>,
+    // Add bytes sent and received to the tooltip for connections that moved any data,
+    // e.g. serial devices
+    pub tooltip_throughput: bool,
+    // Devices kept at the top of the menu, by id
+    pub pinned_devices: Vec<String>,
+    // e.g. "Ctrl+Alt+B"; empty turns the hotkey off
+    pub hotkey: String,
+    // Text written to an idle RFCOMM link on every health check so the device doesn't drop
+    // it, keyed by device id. Off for anything not listed, since stray bytes can confuse a
+    // device that isn't expecting them.
+    pub keepalive: HashMap<String, String>,
+    // Shortens device ids and addresses in "Copy diagnostics"
+    pub redact_diagnostics: bool,
+    // Audio devices made the default output when they connect, by id
+    pub default_audio_devices: Vec<String>,
+    // Turns the switching above off without forgetting the devices
+    pub switch_audio_default: bool,
+    // Switch back to the previous default output once such a device disconnects
+    pub restore_audio_default: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            auto_reconnect: true,
+            reconnect_when_lost: Vec::new(),
+            reconnect_on_resume: true,
+            auto_connect_devices: Vec::new(),
+            auto_connect_cooldown_secs: 120,
+            flaky_after_failures: 3,
+            pause_reconnect_when_flaky: false,
+            notifications: true,
+            connect_timeout_secs: DEFAULT_CONNECT_TIMEOUT.as_secs(),
+            health_check_interval_secs: 30,
+            retry: RetryPolicy::default(),
+            max_concurrent_connects: DEFAULT_MAX_CONCURRENT_CONNECTS,
+            aliases: HashMap::new(),
+            connect_profiles: HashMap::new(),
+            device_order: DeviceOrder::default(),
+            group_by_class: false,
+            connected_first: false,
+            adapter: None,
+            log_to_file: true,
+            favorite_device: None,
+            quick_toggle_device: None,
+            tooltip_throughput: false,
+            pinned_devices: Vec::new(),
+            hotkey: "Ctrl+Alt+B".to_string(),
+            keepalive: HashMap::new(),
+            redact_diagnostics: false,
+            default_audio_devices: Vec::new(),
+            switch_audio_default: true,
+            restore_audio_default: true,
+        }
+    }
+}
+
+// How hard to try before reporting a connect failure; the delay doubles after every attempt
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay_ms: 500,
+        }
+    }
+}
+
+impl RetryPolicy {
+    // Delay before retrying after the given (1-based) failed attempt
+    pub fn delay_after(&self, attempt: u32) -> Duration {
+        Duration::from_millis(self.base_delay_ms.saturating_mul(1 << (attempt - 1).min(16)))
+    }
+}
+
+// How device items are ordered in the menu
+#[derive(Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceOrder {
+    // By display name, ignoring case
+    #[default]
+    Alphabetical,
+    // Whatever order Windows enumerates them in
+    Enumeration,
+}
+
+impl Config {
+    // The defaults when the file is malformed, along with why for the caller to report.
+    // Logging isn't set up yet when this runs, since the settings decide where logs go.
+    pub fn load() -> (Self, Option<String>) {
+        let Some(path) = config_path() else {
+            return (Self::default(), None);
+        };
+        match read(&path) {
+            Ok(config) => (config, None),
+            Err(e) => (Self::default(), Some(format!("{} could not be read: {}", path.display(), e))),
+        }
+    }
+
+    // Like load, but a malformed file is an error rather than the defaults, so a
+    // half-finished edit doesn't throw away the settings in use
+    pub fn reload() -> Result<Self, toml::de::Error> {
+        config_path().map_or_else(|| Ok(Self::default()), |path| read(&path))
+    }
+
+    pub fn save(&self) {
+        let Some(path) = config_path() else {
+            return;
+        };
+        if let Err(e) = self.export(&path) {
+            warn!(path = %path.display(), error = %e, "Failed to save config");
+        }
+    }
+
+    // Like save, for callers that report the failure themselves
+    pub fn try_save(&self) -> io::Result<()> {
+        match config_path() {
+            Some(path) => self.export(&path),
+            None => Err(io::Error::other("there is no %APPDATA% folder to save it in")),
+        }
+    }
+
+    // Writes the settings to any file, e.g. to carry them to another machine
+    pub fn export(&self, path: &Path) -> io::Result<()> {
+        let contents = toml::to_string_pretty(self).map_err(io::Error::other)?;
+        if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, contents)
+    }
+
+    // Reads a file written by export. Unlike the config file itself, a missing file is an
+    // error here rather than the defaults.
+    pub fn import(path: &Path) -> Result<Self, String> {
+        let contents = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        toml::from_str(&contents).map_err(|e| e.to_string())
+    }
+
+    // Adds the per-device settings of an imported config to these, the imported ones
+    // winning where both have an entry for a device. Everything else stays as it is.
+    pub fn merge(&mut self, imported: Config) {
+        merge_map(&mut self.aliases, imported.aliases);
+        merge_map(&mut self.connect_profiles, imported.connect_profiles);
+        merge_map(&mut self.keepalive, imported.keepalive);
+        for (list, added) in [
+            (&mut self.reconnect_when_lost, imported.reconnect_when_lost),
+            (&mut self.auto_connect_devices, imported.auto_connect_devices),
+            (&mut self.pinned_devices, imported.pinned_devices),
+            (&mut self.default_audio_devices, imported.default_audio_devices),
+        ] {
+            for device_id in added {
+                if !contains_device_id(list, &device_id) {
+                    list.push(device_id);
+                }
+            }
+        }
+        if imported.favorite_device.is_some() {
+            self.favorite_device = imported.favorite_device;
+        }
+        if imported.quick_toggle_device.is_some() {
+            self.quick_toggle_device = imported.quick_toggle_device;
+        }
+    }
+
+    // Every device id a setting refers to, sorted, leaving out the "*" profile
+    pub fn device_ids(&self) -> Vec<String> {
+        let mut ids: Vec<String> = self
+            .aliases
+            .keys()
+            .chain(self.connect_profiles.keys().filter(|id| *id != "*"))
+            .chain(self.keepalive.keys())
+            .chain(&self.reconnect_when_lost)
+            .chain(&self.auto_connect_devices)
+            .chain(&self.pinned_devices)
+            .chain(&self.default_audio_devices)
+            .chain(&self.favorite_device)
+            .chain(&self.quick_toggle_device)
+            .cloned()
+            .collect();
+        ids.sort();
+        ids.dedup();
+        ids
+    }
+
+    pub fn connect_timeout(&self) -> Duration {
+        if self.connect_timeout_secs < MIN_CONNECT_TIMEOUT_SECS {
+            warn!(
+                configured = self.connect_timeout_secs,
+                minimum = MIN_CONNECT_TIMEOUT_SECS,
+                "Connect timeout is too short, using the minimum"
+            );
+        }
+        Duration::from_secs(self.connect_timeout_secs.max(MIN_CONNECT_TIMEOUT_SECS))
+    }
+
+    pub fn auto_connect_cooldown(&self) -> Duration {
+        Duration::from_secs(self.auto_connect_cooldown_secs)
+    }
+
+    pub fn adapter_address(&self) -> Option<u64> {
+        let adapter = self.adapter.as_deref()?;
+        let address = parse_address(adapter);
+        if address.is_none() {
+            warn!(adapter, "Ignoring adapter that isn't a Bluetooth address");
+        }
+        address
+    }
+
+    pub fn health_check_interval(&self) -> Duration {
+        if self.health_check_interval_secs < MIN_HEALTH_CHECK_INTERVAL_SECS {
+            warn!(
+                configured = self.health_check_interval_secs,
+                minimum = MIN_HEALTH_CHECK_INTERVAL_SECS,
+                "Health check interval is too short, using the minimum"
+            );
+        }
+        Duration::from_secs(self.health_check_interval_secs.max(MIN_HEALTH_CHECK_INTERVAL_SECS))
+    }
+}
+
+// An imported entry replaces the one for the same device, however either id is spelled
+fn merge_map(map: &mut HashMap<String, String>, imported: HashMap<String, String>) {
+    for (device_id, value) in imported {
+        map.retain(|id, _| !same_device_id(id, &device_id));
+        map.insert(device_id, value);
+    }
+}
+
+// Anything shorter times out before even a nearby device has answered
+const MIN_CONNECT_TIMEOUT_SECS: u64 = 2;
+
+// Anything shorter would keep the radio busy with checks
+const MIN_HEALTH_CHECK_INTERVAL_SECS: u64 = 5;
+
+fn config_path() -> Option<PathBuf> {
+    app_data_dir().map(|dir| dir.join("config.toml"))
+}
+
+// A missing file is the defaults
+fn read(path: &Path) -> Result<Config, toml::de::Error> {
+    match fs::read_to_string(path) {
+        Ok(contents) => toml::from_str(&contents),
+        Err(_) => Ok(Config::default()),
+    }
+}
+
+// Calls on_change with the new settings whenever the config file is saved. Edits that
+// don't parse are logged and skipped. The watcher stops when the returned value is dropped.
+pub fn watch(on_change: impl Fn(Config) + Send + 'static) -> Option<RecommendedWatcher> {
+    let path = config_path()?;
+    // Editors often save by replacing the file, so watch the directory it's in
+    let dir = path.parent()?.to_path_buf();
+    let _ = fs::create_dir_all(&dir);
+
+    let watched = path.clone();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        let event = match event {
+            Ok(event) => event,
+            Err(e) => {
+                warn!(error = %e, "Config watcher error");
+                return;
+            }
+        };
+        // A removed file would read as the defaults; wait for the new one instead
+        if !matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_))
+            || !event.paths.contains(&watched)
+        {
+            return;
+        }
+        match read(&watched) {
+            Ok(config) => on_change(config),
+            Err(e) => warn!(path = %watched.display(), error = %e, "Ignoring config edit that doesn't parse"),
+        }
+    })
+    .inspect_err(|e| warn!(error = %e, "Failed to create the config watcher"))
+    .ok()?;
+    watcher
+        .watch(&dir, RecursiveMode::NonRecursive)
+        .inspect_err(|e| warn!(dir = %dir.display(), error = %e, "Failed to watch the config directory"))
+        .ok()?;
+    debug!(path = %path.display(), "Watching the config file");
+    Some(watcher)
+}
@@ -0,0 +1,1085 @@
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::sync::{Notify, Semaphore};
+use tokio::task::AbortHandle;
+
+use tracing::{debug, info, warn};
+use windows::core::{Error, GUID, HSTRING};
+use windows::Devices::Bluetooth::BluetoothLEDevice;
+use windows::Devices::Bluetooth::GenericAttributeProfile::GattSession;
+use windows::Networking::Sockets::StreamSocket;
+
+use crate::bluetooth::DeviceKind;
+use crate::config::RetryPolicy;
+use crate::platform::{BluetoothPlatform, WindowsPlatform};
+use crate::error::BlueTrayError;
+
+// The live link to a device: an RFCOMM socket for classic devices, or a GATT session
+// (plus the device it keeps alive) for BLE ones
+pub enum DeviceConnection {
+    Rfcomm(StreamSocket),
+    Gatt {
+        device: BluetoothLEDevice,
+        session: GattSession,
+    },
+}
+
+impl DeviceConnection {
+    // Close explicitly so the link is released now rather than
+    // whenever the last COM reference happens to go away
+    pub fn close(&self) -> Result<(), Error> {
+        match self {
+            DeviceConnection::Rfcomm(socket) => socket.Close(),
+            DeviceConnection::Gatt { device, session } => {
+                let session_result = session.Close();
+                device.Close()?;
+                session_result
+            }
+        }
+    }
+}
+
+// Idle -> Connecting -> Connected or Failed. Disconnecting goes back to Idle, and a failed
+// device can start connecting again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConnectionState {
+    // Not known to the manager: never connected, or disconnected since
+    Idle,
+    Connecting,
+    Connected,
+    Failed,
+}
+
+// A device moving from one state to another, as observers hear about it. Idle means
+// disconnected: the manager has forgotten the device.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StateChange {
+    // As first given to the manager
+    pub device_id: String,
+    pub name: String,
+    pub state: ConnectionState,
+}
+
+type Observer = Box<dyn Fn(&StateChange) + Send>;
+
+// Canonical form of a device id, for use as a key. The same device can come back from
+// different WinRT calls with its id in different case, or with stray whitespace.
+pub fn normalize_device_id(device_id: &str) -> String {
+    device_id.trim().to_lowercase()
+}
+
+pub fn same_device_id(a: &str, b: &str) -> bool {
+    a == b || normalize_device_id(a) == normalize_device_id(b)
+}
+
+// For the id lists in the config, which keep ids as the user or the API wrote them
+pub fn contains_device_id(ids: &[String], device_id: &str) -> bool {
+    ids.iter().any(|id| same_device_id(id, device_id))
+}
+
+// Same for the config maps keyed by device id, like the aliases
+pub fn get_by_device_id<'a, V>(map: &'a HashMap<String, V>, device_id: &str) -> Option<&'a V> {
+    map.get(device_id)
+        .or_else(|| map.iter().find(|(id, _)| same_device_id(id, device_id)).map(|(_, value)| value))
+}
+
+// A device the manager knows about, along with its link once connected
+struct Connection<L> {
+    // As first given, for reporting back to callers that compare ids exactly
+    device_id: String,
+    name: String,
+    state: ConnectionState,
+    socket: Option<L>,
+    connected_at: Option<Instant>,
+    // Task pulling incoming data off the link, stopped when the device is disconnected
+    reader: Option<AbortHandle>,
+    // Over this link only; a reconnect starts from zero
+    bytes_sent: u64,
+    bytes_received: u64,
+    // Notified to make the connect task give up instead of retrying
+    stop_retrying: Arc<Notify>,
+    // Task running the connect, while one is in flight
+    connect_task: Option<AbortHandle>,
+}
+
+impl<L> Connection<L> {
+    fn new(device_id: String, name: String) -> Self {
+        Self {
+            device_id,
+            name,
+            state: ConnectionState::Connecting,
+            socket: None,
+            connected_at: None,
+            reader: None,
+            bytes_sent: 0,
+            bytes_received: 0,
+            stop_retrying: Arc::new(Notify::new()),
+            connect_task: None,
+        }
+    }
+}
+
+// Snapshot of a connection for the UI, detached from the manager's lock
+#[derive(Clone, Debug)]
+pub struct ConnectionInfo {
+    pub device_id: String,
+    pub name: String,
+    pub state: ConnectionState,
+    pub connected_since: Option<Instant>,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+// What a throwaway connect to a device found, for the "Test connection" action
+#[derive(Debug)]
+pub struct DeviceTest {
+    // The device was already connected, so its link was checked and left as it is
+    pub existing_link: bool,
+    // How long the connect took, when it got that far
+    pub connect_time: Option<Duration>,
+    // Whether Windows saw the device as connected once the link was open
+    pub link_up: bool,
+    // Whether the probe could be written to the link; None when there was nothing to write
+    pub probe_sent: Option<bool>,
+    pub error: Option<BlueTrayError>,
+    pub elapsed: Duration,
+}
+
+impl DeviceTest {
+    pub fn passed(&self) -> bool {
+        self.error.is_none() && self.link_up && self.probe_sent != Some(false)
+    }
+
+    // e.g. "Passed in 1.4s: connected in 1.2s, test data written"
+    pub fn summary(&self) -> String {
+        let mut details = Vec::new();
+        if self.existing_link {
+            details.push("already connected, link left open".to_string());
+        }
+        if let Some(connect_time) = self.connect_time {
+            details.push(format!("connected in {:.1}s", connect_time.as_secs_f32()));
+        }
+        if let Some(error) = &self.error {
+            details.push(error.to_string());
+        } else if !self.link_up {
+            details.push("Windows does not see the device as connected".to_string());
+        }
+        match self.probe_sent {
+            Some(true) => details.push("test data written".to_string()),
+            Some(false) => details.push("could not write test data".to_string()),
+            None => {}
+        }
+        let verdict = if self.passed() { "Passed" } else { "Failed" };
+        format!("{} in {:.1}s: {}", verdict, self.elapsed.as_secs_f32(), details.join(", "))
+    }
+}
+
+// Connect times kept per device for its summary; older ones drop off
+const CONNECT_TIME_SAMPLES: usize = 20;
+
+// How long a device's recent successful connects took, since bluetray started
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ConnectTimes {
+    pub count: usize,
+    pub min: Duration,
+    pub average: Duration,
+    pub max: Duration,
+}
+
+impl ConnectTimes {
+    // e.g. "min 0.8s, avg 1.1s, max 2.0s over 5 connect(s)"
+    pub fn summary(&self) -> String {
+        format!(
+            "min {:.1}s, avg {:.1}s, max {:.1}s over {} connect(s)",
+            self.min.as_secs_f32(),
+            self.average.as_secs_f32(),
+            self.max.as_secs_f32(),
+            self.count
+        )
+    }
+}
+
+// Connects through the platform, trying again after transient failures the way the policy
+// says. on_retry hears about every retry before its delay, with the attempt about to be
+// made and the most there will be. Once stop is notified, the delay is cut short and the
+// last error returned. The link comes back with how long the attempt that opened it took,
+// from the connect call to the verified link, leaving out failed attempts and delays.
+pub async fn connect_with_retry<P: BluetoothPlatform>(
+    platform: &P,
+    device_id: &HSTRING,
+    kind: DeviceKind,
+    preferred_service: Option<GUID>,
+    retry: RetryPolicy,
+    stop: &Notify,
+    on_retry: impl Fn(u32, u32),
+) -> Result<(P::Link, Duration), BlueTrayError> {
+    let max_attempts = retry.max_attempts.max(1);
+    let mut attempt = 1;
+    loop {
+        let started = Instant::now();
+        match platform.connect(device_id, kind, preferred_service).await {
+            Ok(link) => {
+                let elapsed = started.elapsed();
+                info!(%device_id, attempt, elapsed_ms = elapsed.as_millis() as u64, "Connect attempt succeeded");
+                return Ok((link, elapsed));
+            }
+            Err(e) if e.is_transient() && attempt < max_attempts => {
+                let delay = retry.delay_after(attempt);
+                warn!(
+                    %device_id, attempt, max_attempts, error = %e, ?delay,
+                    "Connect attempt failed, retrying"
+                );
+                attempt += 1;
+                on_retry(attempt, max_attempts);
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {}
+                    _ = stop.notified() => {
+                        info!(%device_id, "Stopped retrying");
+                        return Err(e);
+                    }
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Enough to bring a couple of devices back at once without swamping the Bluetooth stack
+pub const DEFAULT_MAX_CONCURRENT_CONNECTS: usize = 2;
+
+// This struct will manage active Bluetooth connections
+pub struct ConnectionManager<P: BluetoothPlatform = WindowsPlatform> {
+    platform: P,
+    // Keyed by normalized device id; also holds devices that are connecting or whose last attempt failed
+    active_connections: HashMap<String, Connection<P::Link>>,
+    // Connect failures in a row, by normalized device id. Kept apart from the connections so it
+    // survives a disconnect, and reset by the next successful connect.
+    failures: HashMap<String, u32>,
+    // Failures in a row after which a device counts as flaky; 0 never does
+    flaky_after: u32,
+    // The last few successful connect times, by normalized device id; kept in memory only,
+    // so every start begins without any
+    connect_times: HashMap<String, VecDeque<Duration>>,
+    // Connect tasks take a permit before they start, so only this many run at once
+    connect_slots: Arc<Semaphore>,
+    max_concurrent_connects: usize,
+    // Called on every state change, in the order they were added
+    observers: Vec<Observer>,
+}
+
+impl<P: BluetoothPlatform> ConnectionManager<P> {
+    pub fn new(platform: P) -> Self {
+        Self {
+            platform,
+            active_connections: HashMap::new(),
+            failures: HashMap::new(),
+            flaky_after: 0,
+            connect_times: HashMap::new(),
+            connect_slots: Arc::new(Semaphore::new(DEFAULT_MAX_CONCURRENT_CONNECTS)),
+            max_concurrent_connects: DEFAULT_MAX_CONCURRENT_CONNECTS,
+            observers: Vec::new(),
+        }
+    }
+
+    // Has observer called with every state change from now on. It runs under the manager's
+    // lock, so it should hand the change off rather than act on it, e.g. post it to the
+    // event loop.
+    pub fn observe(&mut self, observer: impl Fn(&StateChange) + Send + 'static) {
+        self.observers.push(Box::new(observer));
+    }
+
+    fn changed(&self, device_id: &str, name: &str, state: ConnectionState) {
+        debug!(%device_id, ?state, "Connection state changed");
+        let change = StateChange {
+            device_id: device_id.to_string(),
+            name: name.to_string(),
+            state,
+        };
+        for observer in &self.observers {
+            observer(&change);
+        }
+    }
+
+    pub fn connect_slots(&self) -> Arc<Semaphore> {
+        self.connect_slots.clone()
+    }
+
+    // Attempts already holding a slot of the old limit finish as they are
+    pub fn set_max_concurrent_connects(&mut self, max: usize) {
+        let max = max.max(1);
+        if max != self.max_concurrent_connects {
+            self.max_concurrent_connects = max;
+            self.connect_slots = Arc::new(Semaphore::new(max));
+        }
+    }
+
+    // For settings that change while running, like the connect timeout
+    pub fn platform_mut(&mut self) -> &mut P {
+        &mut self.platform
+    }
+
+    pub fn set_flaky_after(&mut self, failures: u32) {
+        self.flaky_after = failures;
+    }
+
+    // Connect tasks run without the manager's lock, so they get the platform from here
+    pub fn platform(&self) -> &P {
+        &self.platform
+    }
+
+    // Marks a device as connecting before its connect task is spawned. Returns false when
+    // it is already connected or an attempt is still in flight, so no second task is started.
+    pub fn begin_connect(&mut self, device_id: &HSTRING, name: String) -> bool {
+        let device_id_str = device_id.to_string();
+        if device_id_str.trim().is_empty() {
+            warn!("Refusing to connect a device without an id");
+            return false;
+        }
+        if self.is_connected(&device_id_str) || self.is_connecting(&device_id_str) {
+            return false;
+        }
+        // A failed entry normally has no link left, but close one if it does
+        let replaced = self.active_connections.insert(
+            normalize_device_id(&device_id_str),
+            Connection::new(device_id_str.clone(), name.clone()),
+        );
+        if let Some(reader) = replaced.as_ref().and_then(|replaced| replaced.reader.as_ref()) {
+            reader.abort();
+        }
+        if let Some(stale) = replaced.and_then(|replaced| replaced.socket) {
+            if let Err(e) = self.platform.disconnect(&stale) {
+                warn!(device_id = %device_id_str, error = %e, "Failed to close stale connection");
+            }
+        }
+        self.changed(&device_id_str, &name, ConnectionState::Connecting);
+        true
+    }
+
+    // Drops whatever is left of a dead connection, closing its link, and marks the device as
+    // connecting again. The caller starts the connect task, same as after begin_connect.
+    pub fn reconnect(&mut self, device_id: &HSTRING, name: String) -> bool {
+        self.disconnect_device(&device_id.to_string());
+        self.begin_connect(device_id, name)
+    }
+
+    // Records the outcome of a connect task, storing the connection on success
+    pub fn connect_device(
+        &mut self,
+        device_id: &HSTRING,
+        result: Result<P::Link, BlueTrayError>,
+    ) -> Result<(), BlueTrayError> {
+        let device_id_str = device_id.to_string();
+        let key = normalize_device_id(&device_id_str);
+        let Some(connection) = self.active_connections.get_mut(&key) else {
+            // Cancelled or disconnected while the attempt was in flight, so nobody wants it
+            if let Ok(link) = result {
+                debug!(device_id = %device_id_str, "Closing the link of a connect that was called off");
+                if let Err(e) = self.platform.disconnect(&link) {
+                    warn!(device_id = %device_id_str, error = %e, "Failed to close connection");
+                }
+            }
+            return Err(BlueTrayError::Cancelled);
+        };
+        connection.connect_task = None;
+        let original_id = connection.device_id.clone();
+        let name = connection.name.clone();
+
+        let socket = match result {
+            Ok(socket) => socket,
+            Err(e) => {
+                let failed = connection.state != ConnectionState::Connected;
+                if failed {
+                    connection.state = ConnectionState::Failed;
+                }
+                *self.failures.entry(key).or_default() += 1;
+                if failed {
+                    self.changed(&original_id, &name, ConnectionState::Failed);
+                }
+                return Err(e);
+            }
+        };
+
+        self.failures.remove(&key);
+
+        // Check if already connected
+        if connection.state == ConnectionState::Connected {
+            debug!(device_id = %device_id_str, "Device already connected, closing the extra link");
+            let _ = self.platform.disconnect(&socket);
+            return Ok(());
+        }
+
+        // A link left over from an attempt that never got cleaned up would otherwise be
+        // dropped without a close, keeping the device busy
+        if let Some(stale) = connection.socket.replace(socket) {
+            debug!(device_id = %device_id_str, "Closing the stale link being replaced");
+            if let Err(e) = self.platform.disconnect(&stale) {
+                warn!(device_id = %device_id_str, error = %e, "Failed to close stale connection");
+            }
+        }
+        if let Some(reader) = connection.reader.take() {
+            reader.abort();
+        }
+
+        // Store the connection
+        connection.state = ConnectionState::Connected;
+        connection.connected_at = Some(Instant::now());
+        info!(device_id = %device_id_str, active = self.connected_ids().len(), "Connection stored");
+        self.changed(&original_id, &name, ConnectionState::Connected);
+
+        Ok(())
+    }
+
+    // Forgets the device, closing its socket if it had one. Returns whether it was connected.
+    pub fn disconnect_device(&mut self, device_id: &str) -> bool {
+        let connection = self.active_connections.remove(&normalize_device_id(device_id));
+        if let Some(connection) = &connection {
+            if let Some(reader) = &connection.reader {
+                reader.abort();
+            }
+            self.changed(&connection.device_id, &connection.name, ConnectionState::Idle);
+        }
+        let Some(socket) = connection.and_then(|connection| connection.socket) else {
+            debug!(%device_id, "Device not connected");
+            return false;
+        };
+
+        if let Err(e) = self.platform.disconnect(&socket) {
+            warn!(%device_id, error = %e, "Failed to close connection");
+        }
+        info!(%device_id, active = self.connected_ids().len(), "Disconnected from device");
+
+        true
+    }
+
+    // Best-effort teardown of every connection; returns how many were connected
+    pub fn disconnect_all(&mut self) -> usize {
+        let mut disconnected = 0;
+        let connections: Vec<_> = self.active_connections.drain().map(|(_, connection)| connection).collect();
+        for connection in connections {
+            if let Some(reader) = &connection.reader {
+                reader.abort();
+            }
+            self.changed(&connection.device_id, &connection.name, ConnectionState::Idle);
+            let Some(socket) = connection.socket else {
+                continue;
+            };
+            if let Err(e) = self.platform.disconnect(&socket) {
+                warn!(device_id = %connection.device_id, error = %e, "Failed to close connection");
+            }
+            disconnected += 1;
+        }
+        info!(disconnected, "Disconnected all devices");
+
+        disconnected
+    }
+
+    // Ties the connect task to its attempt so it can be cancelled
+    pub fn set_connect_task(&mut self, device_id: &str, task: AbortHandle) {
+        match self.active_connections.get_mut(&normalize_device_id(device_id)) {
+            Some(connection) if connection.state == ConnectionState::Connecting => connection.connect_task = Some(task),
+            _ => task.abort(),
+        }
+    }
+
+    // Calls off a connect in flight and forgets the device, so it's idle and can be connected
+    // again. Aborting the task drops whatever socket it had half open; a result already on
+    // its way is closed when it arrives. Returns whether there was a connect to cancel.
+    pub fn cancel_connect(&mut self, device_id: &str) -> bool {
+        let key = normalize_device_id(device_id);
+        if self.state(&key) != ConnectionState::Connecting {
+            return false;
+        }
+        let Some(connection) = self.active_connections.remove(&key) else {
+            return false;
+        };
+        if let Some(task) = connection.connect_task {
+            task.abort();
+        }
+        if let Some(stale) = connection.socket {
+            if let Err(e) = self.platform.disconnect(&stale) {
+                warn!(%device_id, error = %e, "Failed to close stale connection");
+            }
+        }
+        info!(%device_id, "Connect cancelled");
+        self.changed(&connection.device_id, &connection.name, ConnectionState::Idle);
+        true
+    }
+
+    pub fn link(&self, device_id: &str) -> Option<&P::Link> {
+        self.active_connections
+            .get(&normalize_device_id(device_id))
+            .and_then(|connection| connection.socket.as_ref())
+    }
+
+    pub fn has_reader(&self, device_id: &str) -> bool {
+        self.active_connections
+            .get(&normalize_device_id(device_id))
+            .is_some_and(|connection| connection.reader.is_some())
+    }
+
+    // Ties a reader task to the connection so disconnecting stops it
+    pub fn set_reader(&mut self, device_id: &str, reader: AbortHandle) {
+        match self.active_connections.get_mut(&normalize_device_id(device_id)) {
+            Some(connection) => connection.reader = Some(reader),
+            None => reader.abort(),
+        }
+    }
+
+    // The write to await; await it after letting go of the manager's lock, then record
+    // what went out with record_sent
+    pub fn send(
+        &self,
+        device_id: &str,
+        data: &[u8],
+    ) -> impl Future<Output = Result<(), BlueTrayError>> + Send {
+        let write = match self.link(device_id) {
+            Some(link) => Ok(self.platform.send(device_id, link, data)),
+            None => Err(BlueTrayError::NotConnected(device_id.to_string())),
+        };
+        async move { write?.await }
+    }
+
+    pub fn record_sent(&mut self, device_id: &str, len: usize) {
+        if let Some(connection) = self.active_connections.get_mut(&normalize_device_id(device_id)) {
+            connection.bytes_sent += len as u64;
+        }
+    }
+
+    // Counts what the reader pulled off the link
+    pub fn record_received(&mut self, device_id: &str, len: usize) {
+        if let Some(connection) = self.active_connections.get_mut(&normalize_device_id(device_id)) {
+            connection.bytes_received += len as u64;
+        }
+    }
+
+    // Multi-line snapshot of every connection, for support requests
+    pub fn report(&self) -> String {
+        let mut entries: Vec<_> = self.active_connections.values().collect();
+        entries.sort_by_key(|connection| &connection.device_id);
+
+        let mut report = format!(
+            "bluetray {}: {} connection(s), {} connected\n",
+            env!("CARGO_PKG_VERSION"),
+            entries.len(),
+            self.connected_ids().len()
+        );
+        for connection in entries {
+            report.push_str(&format!("\n{}\n", connection.device_id));
+            report.push_str(&format!("  name: {}\n", connection.name));
+            report.push_str(&format!("  state: {:?}\n", connection.state));
+            if let Some(connected_at) = connection.connected_at {
+                report.push_str(&format!("  connected for: {}s\n", connected_at.elapsed().as_secs()));
+            }
+            if let Some(link) = &connection.socket {
+                report.push_str(&format!("  link: {}\n", self.platform.describe(link)));
+            }
+            report.push_str(&format!("  reader running: {}\n", connection.reader.is_some()));
+            report.push_str(&format!(
+                "  bytes sent: {}, received: {}\n",
+                connection.bytes_sent, connection.bytes_received
+            ));
+        }
+
+        let mut failures: Vec<_> = self.failures.iter().collect();
+        failures.sort();
+        if !failures.is_empty() {
+            report.push_str("\nconsecutive connect failures\n");
+        }
+        for (device_id, count) in failures {
+            report.push_str(&format!("  {}: {}\n", device_id, count));
+        }
+        report
+    }
+
+    // Records how long a successful connect took, as connect_with_retry measured it
+    pub fn record_connect_time(&mut self, device_id: &str, elapsed: Duration) {
+        let samples = self.connect_times.entry(normalize_device_id(device_id)).or_default();
+        if samples.len() == CONNECT_TIME_SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back(elapsed);
+    }
+
+    // None for a device that hasn't connected since bluetray started
+    pub fn connect_times(&self, device_id: &str) -> Option<ConnectTimes> {
+        let samples = self.connect_times.get(&normalize_device_id(device_id))?;
+        let total: Duration = samples.iter().sum();
+        Some(ConnectTimes {
+            count: samples.len(),
+            min: *samples.iter().min()?,
+            average: total / samples.len() as u32,
+            max: *samples.iter().max()?,
+        })
+    }
+
+    pub fn failure_count(&self, device_id: &str) -> u32 {
+        self.failures.get(&normalize_device_id(device_id)).copied().unwrap_or(0)
+    }
+
+    // Whether the device failed to connect often enough in a row to need looking at,
+    // e.g. re-pairing
+    pub fn is_flaky(&self, device_id: &str) -> bool {
+        self.flaky_after > 0 && self.failure_count(device_id) >= self.flaky_after
+    }
+
+    pub fn state(&self, device_id: &str) -> ConnectionState {
+        self.active_connections
+            .get(&normalize_device_id(device_id))
+            .map_or(ConnectionState::Idle, |connection| connection.state)
+    }
+
+    pub fn is_connected(&self, device_id: &str) -> bool {
+        self.state(device_id) == ConnectionState::Connected
+    }
+
+    pub fn is_connecting(&self, device_id: &str) -> bool {
+        self.state(device_id) == ConnectionState::Connecting
+    }
+
+    pub fn has_connections(&self) -> bool {
+        self.active_connections
+            .values()
+            .any(|connection| connection.state == ConnectionState::Connected)
+    }
+
+    // Whether any connect attempt is still in flight
+    pub fn has_pending_connects(&self) -> bool {
+        self.active_connections
+            .values()
+            .any(|connection| connection.state == ConnectionState::Connecting)
+    }
+
+    // Connects to the device on a link of its own, checks the link, writes the probe if
+    // there is one, and closes it again. Goes through one attempt only, so the result says
+    // how the device does rather than how the retries do. A device that is already connected
+    // keeps its link; the test only asks Windows whether it is still up.
+    pub fn test_device(
+        &self,
+        device_id: &HSTRING,
+        kind: DeviceKind,
+        preferred_service: Option<GUID>,
+        probe: Option<Vec<u8>>,
+    ) -> impl Future<Output = DeviceTest>
+    where
+        P: Clone,
+    {
+        let platform = self.platform.clone();
+        let device_id = device_id.clone();
+        let existing_link = self.is_connected(&device_id.to_string());
+        async move {
+            let started = Instant::now();
+            let mut test = DeviceTest {
+                existing_link,
+                connect_time: None,
+                link_up: false,
+                probe_sent: None,
+                error: None,
+                elapsed: Duration::ZERO,
+            };
+            if existing_link {
+                test.link_up = platform.is_connected(&device_id).await;
+                test.elapsed = started.elapsed();
+                return test;
+            }
+
+            match platform.connect(&device_id, kind, preferred_service).await {
+                Ok(link) => {
+                    test.connect_time = Some(started.elapsed());
+                    test.link_up = platform.is_connected(&device_id).await;
+                    if let Some(probe) = probe {
+                        let sent = platform.send(&device_id.to_string(), &link, &probe).await;
+                        test.probe_sent = Some(sent.is_ok());
+                    }
+                    if let Err(e) = platform.disconnect(&link) {
+                        warn!(%device_id, error = %e, "Failed to close the test connection");
+                    }
+                }
+                Err(e) => test.error = Some(e),
+            }
+            test.elapsed = started.elapsed();
+            test
+        }
+    }
+
+    // Handed to the connect task so it can be told to stop retrying
+    pub fn retry_stop(&self, device_id: &str) -> Arc<Notify> {
+        self.active_connections
+            .get(&normalize_device_id(device_id))
+            .map_or_else(|| Arc::new(Notify::new()), |connection| connection.stop_retrying.clone())
+    }
+
+    // Cuts the backoff of a connect in flight short; the attempt already underway still
+    // finishes. Returns whether there was a connect to stop.
+    pub fn stop_retrying(&self, device_id: &str) -> bool {
+        match self.active_connections.get(&normalize_device_id(device_id)) {
+            Some(connection) if connection.state == ConnectionState::Connecting => {
+                connection.stop_retrying.notify_one();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn connected_ids(&self) -> Vec<String> {
+        self.active_connections
+            .values()
+            .filter(|connection| connection.state == ConnectionState::Connected)
+            .map(|connection| connection.device_id.clone())
+            .collect()
+    }
+
+    pub fn list_connections(&self) -> Vec<ConnectionInfo> {
+        self.active_connections
+            .values()
+            .map(|connection| ConnectionInfo {
+                device_id: connection.device_id.clone(),
+                name: connection.name.clone(),
+                state: connection.state,
+                connected_since: connection.connected_at,
+                bytes_sent: connection.bytes_sent,
+                bytes_received: connection.bytes_received,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::platform::mock::{connect, MockPlatform};
+
+    #[tokio::test]
+    async fn double_connect_keeps_the_first_link() {
+        let mut manager = ConnectionManager::new(MockPlatform::default());
+        let device_id = HSTRING::from("device-a");
+        connect(&mut manager, &device_id).await;
+
+        assert!(!manager.begin_connect(&device_id, "device-a".to_string()));
+        manager.connect_device(&device_id, Ok(2)).unwrap();
+
+        assert_eq!(manager.connected_ids(), vec!["device-a".to_string()]);
+        assert_eq!(*manager.platform().closed.borrow(), vec![2]);
+    }
+
+    #[tokio::test]
+    async fn replacing_a_stale_link_closes_it() {
+        let mut manager = ConnectionManager::new(MockPlatform::default());
+        let device_id = HSTRING::from("device-a");
+        connect(&mut manager, &device_id).await;
+        // As if the entry had gone back to connecting without its link being pruned
+        manager.active_connections.get_mut("device-a").unwrap().state = ConnectionState::Connecting;
+
+        manager.connect_device(&device_id, Ok(2)).unwrap();
+
+        assert_eq!(*manager.platform().closed.borrow(), vec![1]);
+        assert_eq!(manager.link("device-a"), Some(&2));
+    }
+
+    #[test]
+    fn second_begin_connect_is_refused_while_connecting() {
+        let mut manager = ConnectionManager::new(MockPlatform::default());
+        let device_id = HSTRING::from("device-a");
+
+        assert!(manager.begin_connect(&device_id, "device-a".to_string()));
+        assert!(!manager.begin_connect(&device_id, "device-a".to_string()));
+        assert_eq!(manager.state("device-a"), ConnectionState::Connecting);
+    }
+
+    #[tokio::test]
+    async fn disconnect_removes_the_entry() {
+        let mut manager = ConnectionManager::new(MockPlatform::default());
+        let device_id = HSTRING::from("device-a");
+        connect(&mut manager, &device_id).await;
+
+        assert!(manager.disconnect_device("device-a"));
+        assert!(!manager.disconnect_device("device-a"));
+        assert_eq!(manager.state("device-a"), ConnectionState::Idle);
+        assert!(manager.list_connections().is_empty());
+        assert_eq!(*manager.platform().closed.borrow(), vec![1]);
+    }
+
+    #[tokio::test]
+    async fn counts_only_connected_devices() {
+        let mut manager = ConnectionManager::new(MockPlatform::default());
+        connect(&mut manager, &HSTRING::from("device-a")).await;
+        connect(&mut manager, &HSTRING::from("device-b")).await;
+        manager.begin_connect(&HSTRING::from("device-c"), "device-c".to_string());
+        let failed = HSTRING::from("device-d");
+        manager.begin_connect(&failed, "device-d".to_string());
+        let _ = manager.connect_device(&failed, Err(BlueTrayError::ConnectTimeout(Default::default())));
+
+        assert_eq!(manager.connected_ids().len(), 2);
+        assert_eq!(manager.list_connections().len(), 4);
+        assert_eq!(manager.state("device-d"), ConnectionState::Failed);
+        assert_eq!(manager.disconnect_all(), 2);
+        assert!(!manager.has_connections());
+    }
+
+    #[tokio::test]
+    async fn failures_count_until_the_next_success() {
+        let mut manager = ConnectionManager::new(MockPlatform::default());
+        manager.set_flaky_after(2);
+        let device_id = HSTRING::from("device-a");
+        for _ in 0..2 {
+            manager.begin_connect(&device_id, "device-a".to_string());
+            let _ = manager.connect_device(&device_id, Err(BlueTrayError::ConnectTimeout(Default::default())));
+            manager.disconnect_device("device-a");
+        }
+
+        assert_eq!(manager.failure_count("device-a"), 2);
+        assert!(manager.is_flaky("device-a"));
+
+        connect(&mut manager, &device_id).await;
+        assert_eq!(manager.failure_count("device-a"), 0);
+        assert!(!manager.is_flaky("device-a"));
+    }
+
+    #[tokio::test]
+    async fn send_needs_a_connected_device() {
+        let mut manager = ConnectionManager::new(MockPlatform::default());
+        let device_id = HSTRING::from("device-a");
+        manager.begin_connect(&device_id, "device-a".to_string());
+
+        assert!(matches!(manager.send("device-a", b"hi").await, Err(BlueTrayError::NotConnected(_))));
+
+        let link = manager
+            .platform()
+            .connect(&device_id, DeviceKind::Classic, None)
+            .await;
+        manager.connect_device(&device_id, link).unwrap();
+        manager.send("device-a", b"hi").await.unwrap();
+        assert_eq!(*manager.platform().sent.borrow(), vec![(1, b"hi".to_vec())]);
+    }
+
+    #[tokio::test]
+    async fn byte_counts_start_over_on_reconnect() {
+        let mut manager = ConnectionManager::new(MockPlatform::default());
+        let device_id = HSTRING::from("device-a");
+        connect(&mut manager, &device_id).await;
+        manager.send("device-a", b"hello").await.unwrap();
+        manager.record_sent("device-a", 5);
+        manager.record_received("device-a", 3);
+
+        let connection = &manager.list_connections()[0];
+        assert_eq!((connection.bytes_sent, connection.bytes_received), (5, 3));
+
+        manager.reconnect(&device_id, "device-a".to_string());
+        let connection = &manager.list_connections()[0];
+        assert_eq!((connection.bytes_sent, connection.bytes_received), (0, 0));
+    }
+
+    #[test]
+    fn normalizing_ignores_case_and_surrounding_whitespace() {
+        assert_eq!(
+            normalize_device_id("  Bluetooth#Bluetooth00:1A:7D:DA:71:13-AA:BB:CC:DD:EE:FF\n"),
+            "bluetooth#bluetooth00:1a:7d:da:71:13-aa:bb:cc:dd:ee:ff"
+        );
+    }
+
+    #[tokio::test]
+    async fn ids_differing_in_case_or_whitespace_are_the_same_device() {
+        let mut manager = ConnectionManager::new(MockPlatform::default());
+        connect(&mut manager, &HSTRING::from("Device-A")).await;
+
+        assert!(manager.is_connected("device-a"));
+        assert!(manager.is_connected(" DEVICE-A "));
+        assert!(!manager.begin_connect(&HSTRING::from("device-a "), "device-a".to_string()));
+        // Reported the way it was first given
+        assert_eq!(manager.connected_ids(), vec!["Device-A".to_string()]);
+
+        assert!(manager.disconnect_device("DEVICE-a"));
+        assert!(manager.list_connections().is_empty());
+    }
+
+    #[tokio::test]
+    async fn failures_count_across_id_spellings() {
+        let mut manager = ConnectionManager::new(MockPlatform::default());
+        manager.begin_connect(&HSTRING::from("Device-A"), "device-a".to_string());
+        let _ = manager.connect_device(
+            &HSTRING::from("device-a"),
+            Err(BlueTrayError::ConnectTimeout(Default::default())),
+        );
+
+        assert_eq!(manager.failure_count(" DEVICE-A"), 1);
+        assert_eq!(manager.list_connections().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_connection_closes_its_own_link() {
+        let manager = ConnectionManager::new(MockPlatform::default());
+        let device_id = HSTRING::from("device-a");
+
+        let test = manager
+            .test_device(&device_id, DeviceKind::Classic, None, Some(b"ping".to_vec()))
+            .await;
+
+        assert!(test.passed(), "{}", test.summary());
+        assert_eq!(test.probe_sent, Some(true));
+        assert_eq!(*manager.platform().closed.borrow(), vec![1]);
+        assert!(manager.list_connections().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_connection_leaves_an_open_link_alone() {
+        let mut manager = ConnectionManager::new(MockPlatform::default());
+        let device_id = HSTRING::from("device-a");
+        connect(&mut manager, &device_id).await;
+
+        let test = manager.test_device(&device_id, DeviceKind::Classic, None, None).await;
+
+        assert!(test.passed() && test.existing_link);
+        assert!(manager.platform().closed.borrow().is_empty());
+        assert_eq!(manager.link("device-a"), Some(&1));
+    }
+
+    #[tokio::test]
+    async fn retries_transient_failures_until_one_works() {
+        let platform = MockPlatform::default();
+        platform.failures.set(2);
+        let retry = RetryPolicy {
+            max_attempts: 3,
+            base_delay_ms: 1,
+        };
+        let retries = std::cell::RefCell::new(Vec::new());
+
+        let link = connect_with_retry(
+            &platform,
+            &HSTRING::from("device-a"),
+            DeviceKind::Classic,
+            None,
+            retry,
+            &Notify::new(),
+            |attempt, max_attempts| retries.borrow_mut().push((attempt, max_attempts)),
+        )
+        .await;
+
+        assert_eq!(link.unwrap().0, 1);
+        assert_eq!(*retries.borrow(), vec![(2, 3), (3, 3)]);
+    }
+
+    #[tokio::test]
+    async fn stopping_gives_up_at_the_next_delay() {
+        let mut manager = ConnectionManager::new(MockPlatform::default());
+        manager.platform().failures.set(5);
+        let device_id = HSTRING::from("device-a");
+        manager.begin_connect(&device_id, "device-a".to_string());
+        assert!(manager.stop_retrying("device-a"));
+        let retry = RetryPolicy {
+            max_attempts: 5,
+            base_delay_ms: 60_000,
+        };
+
+        let stop = manager.retry_stop("device-a");
+        let result =
+            connect_with_retry(manager.platform(), &device_id, DeviceKind::Classic, None, retry, &stop, |_, _| {})
+                .await;
+
+        assert!(matches!(result, Err(BlueTrayError::ConnectTimeout(_))));
+        assert_eq!(manager.platform().failures.get(), 4);
+        let _ = manager.connect_device(&device_id, result.map(|(link, _)| link));
+        assert_eq!(manager.state("device-a"), ConnectionState::Failed);
+        assert!(!manager.stop_retrying("device-a"));
+    }
+
+    #[tokio::test]
+    async fn cancelling_frees_the_device_for_the_next_connect() {
+        let mut manager = ConnectionManager::new(MockPlatform::default());
+        let device_id = HSTRING::from("device-a");
+        manager.begin_connect(&device_id, "device-a".to_string());
+        let task = tokio::spawn(std::future::pending::<()>());
+        manager.set_connect_task("device-a", task.abort_handle());
+
+        assert!(manager.cancel_connect("device-a"));
+        assert!(task.await.unwrap_err().is_cancelled());
+        assert_eq!(manager.state("device-a"), ConnectionState::Idle);
+        assert!(!manager.cancel_connect("device-a"));
+        assert!(manager.begin_connect(&device_id, "device-a".to_string()));
+    }
+
+    #[tokio::test]
+    async fn a_result_after_cancelling_is_closed() {
+        let mut manager = ConnectionManager::new(MockPlatform::default());
+        let device_id = HSTRING::from("device-a");
+        manager.begin_connect(&device_id, "device-a".to_string());
+        manager.cancel_connect("device-a");
+
+        let result = manager.connect_device(&device_id, Ok(7));
+
+        assert!(matches!(result, Err(BlueTrayError::Cancelled)));
+        assert_eq!(*manager.platform().closed.borrow(), vec![7]);
+        assert!(manager.list_connections().is_empty());
+        assert_eq!(manager.failure_count("device-a"), 0);
+    }
+
+    #[test]
+    fn blank_ids_are_refused() {
+        let mut manager = ConnectionManager::new(MockPlatform::default());
+
+        assert!(!manager.begin_connect(&HSTRING::from("  "), "nameless".to_string()));
+        assert!(manager.list_connections().is_empty());
+    }
+
+    fn observed(manager: &mut ConnectionManager<MockPlatform>) -> std::sync::mpsc::Receiver<(String, ConnectionState)> {
+        let (changes, observed) = std::sync::mpsc::channel();
+        manager.observe(move |change| {
+            let _ = changes.send((change.device_id.clone(), change.state));
+        });
+        observed
+    }
+
+    #[tokio::test]
+    async fn observers_hear_every_transition() {
+        let mut manager = ConnectionManager::new(MockPlatform::default());
+        let observed = observed(&mut manager);
+        let device_id = HSTRING::from("Device-A");
+
+        manager.begin_connect(&device_id, "device-a".to_string());
+        let _ = manager.connect_device(&device_id, Err(BlueTrayError::ConnectTimeout(Default::default())));
+        manager.begin_connect(&device_id, "device-a".to_string());
+        manager.connect_device(&device_id, Ok(1)).unwrap();
+        manager.disconnect_device("device-a");
+
+        let states: Vec<_> = observed.try_iter().collect();
+        let expected = [
+            ConnectionState::Connecting,
+            ConnectionState::Failed,
+            ConnectionState::Connecting,
+            ConnectionState::Connected,
+            ConnectionState::Idle,
+        ];
+        assert_eq!(states, expected.map(|state| ("Device-A".to_string(), state)));
+    }
+
+    #[tokio::test]
+    async fn refused_and_extra_links_are_not_transitions() {
+        let mut manager = ConnectionManager::new(MockPlatform::default());
+        let device_id = HSTRING::from("device-a");
+        connect(&mut manager, &device_id).await;
+        let observed = observed(&mut manager);
+
+        assert!(!manager.begin_connect(&device_id, "device-a".to_string()));
+        manager.connect_device(&device_id, Ok(2)).unwrap();
+        manager.cancel_connect("device-a");
+
+        assert_eq!(observed.try_iter().count(), 0);
+    }
+
+    #[test]
+    fn connect_times_cover_the_latest_connects() {
+        let mut manager = ConnectionManager::new(MockPlatform::default());
+        assert_eq!(manager.connect_times("device-a"), None);
+
+        for secs in 1..=CONNECT_TIME_SAMPLES as u64 + 5 {
+            manager.record_connect_time("device-a", Duration::from_secs(secs));
+        }
+
+        let times = manager.connect_times("Device-A").unwrap();
+        assert_eq!(times.count, CONNECT_TIME_SAMPLES);
+        assert_eq!(times.min, Duration::from_secs(6));
+        assert_eq!(times.max, Duration::from_secs(25));
+        assert_eq!(times.average, Duration::from_millis(15_500));
+    }
+}
@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+use tracing::{info, info_span, warn, Instrument};
+use windows::core::HSTRING;
+
+use crate::bluetooth::PairedDevice;
+use crate::config::Config;
+use crate::connection::{connect_with_retry, ConnectionManager, DeviceConnection};
+use crate::error::BlueTrayError;
+use crate::logging;
+use crate::menu::preferred_service;
+use crate::platform::{BluetoothPlatform, WindowsPlatform};
+use crate::state::SavedState;
+
+type ConnectResult = (HSTRING, Result<(DeviceConnection, Duration), BlueTrayError>);
+
+// Runs without the tray: keeps the devices that were connected when the tray last exited
+// connected, reconnecting them on every health check tick they're found down, until Ctrl+C.
+// The saved list is left as it is, so the tray picks up the same devices next time.
+pub async fn run(config: Config, saved_state: SavedState) {
+    let platform = WindowsPlatform::from_config(&config);
+    let mut manager = ConnectionManager::new(platform);
+    manager.set_flaky_after(config.flaky_after_failures);
+    manager.set_max_concurrent_connects(config.max_concurrent_connects);
+
+    let wanted: Vec<HSTRING> = if config.auto_reconnect {
+        saved_state.connected_devices.iter().map(HSTRING::from).collect()
+    } else {
+        Vec::new()
+    };
+    if wanted.is_empty() {
+        warn!("No devices to keep connected; connect them from the tray once so they're remembered");
+    }
+    info!(devices = wanted.len(), "Running without the tray");
+
+    let (results_tx, mut results) = mpsc::unbounded_channel::<ConnectResult>();
+    let mut devices: HashMap<String, PairedDevice> = HashMap::new();
+    let mut ticker = tokio::time::interval(config.health_check_interval());
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {
+                // Listed again until it works, e.g. when started before the radio is up
+                if devices.is_empty() && !wanted.is_empty() {
+                    match platform.list_paired_devices().await {
+                        Ok(paired) => {
+                            devices = paired.into_iter().map(|device| (device.device_id.to_string(), device)).collect();
+                        }
+                        Err(e) => {
+                            warn!(error = %e, "Failed to list paired devices");
+                            continue;
+                        }
+                    }
+                }
+
+                for device_id in manager.connected_ids() {
+                    if !platform.is_connected(&HSTRING::from(&device_id)).await {
+                        warn!(%device_id, "Connection lost");
+                        manager.disconnect_device(&device_id);
+                    }
+                }
+
+                for device_id in &wanted {
+                    let Some(device) = devices.get(&device_id.to_string()) else {
+                        continue;
+                    };
+                    if config.pause_reconnect_when_flaky && manager.is_flaky(&device_id.to_string()) {
+                        continue;
+                    }
+                    if manager.begin_connect(device_id, device.name.clone()) {
+                        spawn_connect(&manager, &config, device, results_tx.clone());
+                    }
+                }
+            }
+            Some((device_id, result)) = results.recv() => {
+                let connect_time = result.as_ref().ok().map(|(_, elapsed)| *elapsed);
+                match manager.connect_device(&device_id, result.map(|(link, _)| link)) {
+                    // Nothing here shows diagnostics, so the time only goes to the log
+                    Ok(()) => info!(%device_id, ?connect_time, "Connected"),
+                    Err(e) => {
+                        let failures = manager.failure_count(&device_id.to_string());
+                        warn!(%device_id, error = %e, failures, "Failed to connect");
+                    }
+                }
+            }
+            _ = tokio::signal::ctrl_c() => {
+                info!("Stopping");
+                manager.disconnect_all();
+                logging::flush();
+                break;
+            }
+        }
+    }
+}
+
+// Same as the tray's connect task, reporting back over the channel instead of the event loop
+fn spawn_connect(
+    manager: &ConnectionManager,
+    config: &Config,
+    device: &PairedDevice,
+    results: mpsc::UnboundedSender<ConnectResult>,
+) {
+    let platform = *manager.platform();
+    let slots = manager.connect_slots();
+    let device_id = device.device_id.clone();
+    let kind = device.kind;
+    let preferred = preferred_service(&config.connect_profiles, &device_id);
+    let retry = config.retry;
+    let stop = manager.retry_stop(&device_id.to_string());
+    let span = info_span!("connect", %device_id, ?kind);
+    tokio::spawn(
+        async move {
+            let _slot = slots.acquire_owned().await;
+            let result =
+                connect_with_retry(&platform, &device_id, kind, preferred, retry, &stop, |_, _| {}).await;
+            let _ = results.send((device_id, result));
+        }
+        .instrument(span),
+    );
+}
@@ -0,0 +1,94 @@
+use std::fs;
+use std::path::PathBuf;
+
+use crate::bluetooth::format_address;
+use crate::connection::ConnectionManager;
+use crate::logging;
+use crate::menu::DeviceMenu;
+use crate::state::app_data_dir;
+
+// How much of the newest log file goes into the bundle
+const LOG_TAIL_LINES: usize = 200;
+
+// Characters of a device id kept when redacting, enough to tell devices apart
+const REDACTED_ID_LEN: usize = 8;
+
+// Crate version plus the commit it was built from, when the build could tell
+pub fn build_version() -> String {
+    match option_env!("BLUETRAY_GIT_HASH") {
+        Some(hash) => format!("{} ({})", env!("CARGO_PKG_VERSION"), hash),
+        None => env!("CARGO_PKG_VERSION").to_string(),
+    }
+}
+
+// Everything a bug report needs in one blob: versions, paired devices, connections and
+// the end of the log. With redact, device ids and addresses are cut down wherever they
+// appear.
+pub fn collect(device_menu: &DeviceMenu, manager: &ConnectionManager, redact: bool) -> String {
+    let mut report = format!(
+        "bluetray {}\n{} ({})\n",
+        build_version(),
+        logging::os_version(),
+        std::env::consts::ARCH
+    );
+
+    let mut devices: Vec<_> = device_menu.devices.values().collect();
+    devices.sort_by_cached_key(|entry| entry.name.to_lowercase());
+    report.push_str(&format!("\nPaired devices ({})\n", devices.len()));
+    for entry in &devices {
+        report.push_str(&format!(
+            "  {} [{:?}, {}{}]\n    id: {}\n",
+            entry.name,
+            entry.kind,
+            entry.class.label(),
+            if entry.connectable { "" } else { ", not connectable" },
+            entry.device_id
+        ));
+        if let Some(address) = entry.address {
+            report.push_str(&format!("    address: {}\n", format_address(address)));
+        }
+        if let Some(times) = manager.connect_times(&entry.device_id.to_string()) {
+            report.push_str(&format!("    connect time: {}\n", times.summary()));
+        }
+    }
+
+    report.push_str(&format!("\nConnections\n{}", manager.report()));
+
+    report.push_str("\nLog\n");
+    match log_tail() {
+        Some(tail) => report.push_str(&tail),
+        None => report.push_str("(no log file)\n"),
+    }
+
+    if redact {
+        for entry in &devices {
+            let device_id = entry.device_id.to_string();
+            report = report.replace(&device_id, &redact_id(&device_id));
+            if let Some(address) = entry.address {
+                let address = format_address(address);
+                report = report.replace(&address, &redact_id(&address));
+            }
+        }
+    }
+    report
+}
+
+// Keeps the tail end, which is where ids and addresses differ from one device to the next
+fn redact_id(id: &str) -> String {
+    let kept = id.char_indices().rev().nth(REDACTED_ID_LEN - 1).map_or(id, |(start, _)| &id[start..]);
+    format!("…{}", kept)
+}
+
+// The last lines of the newest log file; the daily file names sort by date
+fn log_tail() -> Option<String> {
+    let dir: PathBuf = app_data_dir()?.join("logs");
+    let newest = fs::read_dir(dir)
+        .ok()?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|extension| extension == "log"))
+        .max()?;
+    let contents = fs::read_to_string(newest).ok()?;
+    let lines: Vec<&str> = contents.lines().collect();
+    let start = lines.len().saturating_sub(LOG_TAIL_LINES);
+    Some(lines[start..].iter().map(|line| format!("{}\n", line)).collect())
+}
@@ -0,0 +1,85 @@
+use std::fmt;
+use std::time::Duration;
+
+// Everything that can go wrong while talking to a Bluetooth device, split
+// out so the UI can tell the user what actually happened
+#[derive(Debug)]
+pub enum BlueTrayError {
+    DeviceNotFound(String),
+    NotConnected(String),
+    NoRfcommService(String),
+    NoGattService(String),
+    ConnectTimeout(Duration),
+    // The socket opened, but the device never came up behind it
+    LinkNotEstablished(String),
+    PairingFailed(String),
+    UnpairingFailed(String),
+    RadioOff,
+    RadioAccessDenied,
+    // Enumerating devices took longer than this, e.g. a Bluetooth stack that stopped answering
+    NotResponding(Duration),
+    // The connect was called off from the menu before it finished
+    Cancelled,
+    Windows(windows::core::Error),
+}
+
+impl BlueTrayError {
+    // Whether trying again might help, e.g. a sleeping headset that needs a moment to wake up
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            BlueTrayError::ConnectTimeout(_) | BlueTrayError::LinkNotEstablished(_) | BlueTrayError::Windows(_)
+        )
+    }
+}
+
+impl fmt::Display for BlueTrayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlueTrayError::DeviceNotFound(device_id) => {
+                write!(f, "Device not found: {}", device_id)
+            }
+            BlueTrayError::NotConnected(device_id) => {
+                write!(f, "Device is not connected: {}", device_id)
+            }
+            BlueTrayError::NoRfcommService(device_id) => {
+                write!(f, "Device has no RFCOMM service to connect to: {}", device_id)
+            }
+            BlueTrayError::NoGattService(device_id) => {
+                write!(f, "Device has no reachable GATT services: {}", device_id)
+            }
+            BlueTrayError::ConnectTimeout(timeout) => {
+                write!(f, "Timed out after {:?} connecting to device", timeout)
+            }
+            BlueTrayError::LinkNotEstablished(device_id) => {
+                write!(f, "Connected, but the device did not respond: {}", device_id)
+            }
+            BlueTrayError::PairingFailed(status) => write!(f, "Pairing did not complete ({})", status),
+            BlueTrayError::UnpairingFailed(status) => write!(f, "Windows did not remove the device ({})", status),
+            BlueTrayError::RadioOff => write!(f, "Bluetooth is turned off"),
+            BlueTrayError::RadioAccessDenied => {
+                write!(f, "Windows did not allow switching the Bluetooth radio")
+            }
+            BlueTrayError::Cancelled => write!(f, "The connect was cancelled"),
+            BlueTrayError::NotResponding(timeout) => {
+                write!(f, "Bluetooth did not respond within {:?}", timeout)
+            }
+            BlueTrayError::Windows(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for BlueTrayError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            BlueTrayError::Windows(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<windows::core::Error> for BlueTrayError {
+    fn from(e: windows::core::Error) -> Self {
+        BlueTrayError::Windows(e)
+    }
+}
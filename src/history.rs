@@ -0,0 +1,116 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use time::macros::format_description;
+use time::{OffsetDateTime, UtcOffset};
+use tracing::warn;
+
+use crate::state::app_data_dir;
+
+// Older entries are dropped once the history grows past this
+const MAX_ENTRIES: usize = 50;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HistoryAction {
+    Connect,
+    Disconnect,
+    // The device dropped off by itself
+    Lost,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub device_id: String,
+    pub name: String,
+    pub action: HistoryAction,
+    // Seconds since the Unix epoch
+    pub timestamp: u64,
+    // Why a connect failed; None when it worked
+    pub error: Option<String>,
+}
+
+impl HistoryEntry {
+    // e.g. "Oct 14 09:30  Headset: connect failed"
+    pub fn label(&self) -> String {
+        let what = match (self.action, &self.error) {
+            (HistoryAction::Connect, None) => "connected",
+            (HistoryAction::Connect, Some(_)) => "connect failed",
+            (HistoryAction::Disconnect, _) => "disconnected",
+            (HistoryAction::Lost, _) => "connection lost",
+        };
+        format!("{}  {}: {}", format_timestamp(self.timestamp), self.name, what)
+    }
+}
+
+// Recent connects and disconnects, newest last, kept in %APPDATA%/bluetray/history.json
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct History {
+    pub entries: VecDeque<HistoryEntry>,
+}
+
+impl History {
+    pub fn load() -> Self {
+        let Some(path) = history_path() else {
+            return Self::default();
+        };
+        match fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+                warn!(path = %path.display(), error = %e, "Ignoring unreadable history file");
+                Self::default()
+            }),
+            Err(_) => Self::default(),
+        }
+    }
+
+    pub fn record(&mut self, device_id: &str, name: &str, action: HistoryAction, error: Option<String>) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        self.entries.push_back(HistoryEntry {
+            device_id: device_id.to_string(),
+            name: name.to_string(),
+            action,
+            timestamp,
+            error,
+        });
+        while self.entries.len() > MAX_ENTRIES {
+            self.entries.pop_front();
+        }
+        self.save();
+    }
+
+    fn save(&self) {
+        let Some(path) = history_path() else {
+            return;
+        };
+        let result = path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|()| {
+                let contents = serde_json::to_string_pretty(self).map_err(std::io::Error::other)?;
+                fs::write(&path, contents)
+            });
+        if let Err(e) = result {
+            warn!(path = %path.display(), error = %e, "Failed to save history");
+        }
+    }
+}
+
+// In local time when Windows tells us the offset, UTC otherwise
+fn format_timestamp(timestamp: u64) -> String {
+    let Ok(time) = OffsetDateTime::from_unix_timestamp(timestamp as i64) else {
+        return "?".to_string();
+    };
+    let time = time.to_offset(UtcOffset::current_local_offset().unwrap_or(UtcOffset::UTC));
+    time.format(format_description!("[month repr:short] [day] [hour]:[minute]"))
+        .unwrap_or_else(|_| "?".to_string())
+}
+
+fn history_path() -> Option<PathBuf> {
+    app_data_dir().map(|dir| dir.join("history.json"))
+}
@@ -0,0 +1,189 @@
+use tracing::warn;
+use tray_icon::menu::Icon as MenuIcon;
+use tray_icon::Icon;
+
+use crate::bluetooth::DeviceClass;
+
+const ICON_SIZE: u32 = 32;
+
+// The tray icon itself; the state is shown as a badge in its corner
+const ICON_PNG: &[u8] = include_bytes!("../icon.png");
+
+// Badge in the bottom-right corner, sized to stay readable at tray size
+const BADGE_RADIUS: f32 = 6.0;
+
+const CONNECTED_COLOR: [u8; 4] = [0, 170, 70, 255];
+const DISCONNECTED_COLOR: [u8; 4] = [128, 128, 128, 255];
+const RADIO_OFF_COLOR: [u8; 4] = [200, 40, 40, 255];
+const CONNECTING_COLOR: [u8; 4] = [235, 160, 0, 255];
+
+// Frames in the connecting animation, which pulses the badge from grey to amber and back
+pub const CONNECTING_FRAMES: usize = 6;
+
+// Glyphs next to each device in the menu, by class; Other gets the Bluetooth rune
+const AUDIO_PNG: &[u8] = include_bytes!("../icons/audio.png");
+const INPUT_PNG: &[u8] = include_bytes!("../icons/input.png");
+const PHONE_PNG: &[u8] = include_bytes!("../icons/phone.png");
+const OTHER_PNG: &[u8] = include_bytes!("../icons/other.png");
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum TrayState {
+    Connected,
+    Disconnected,
+    RadioOff,
+    // One frame of the animation shown while a connect is in flight
+    Connecting(usize),
+}
+
+// Tray icons for each state, generated once at startup. None only if even the plain
+// circle couldn't be made, which leaves the tray without an icon rather than the app down.
+pub struct TrayIcons {
+    connected: Option<Icon>,
+    disconnected: Option<Icon>,
+    radio_off: Option<Icon>,
+    connecting: Vec<Option<Icon>>,
+}
+
+impl TrayIcons {
+    pub fn new() -> Self {
+        // Plain circles still tell the states apart, so the app can launch anyway
+        let base = decode_png(ICON_PNG)
+            .inspect_err(|e| warn!(error = %e, "Failed to decode the tray icon, using plain circles"))
+            .ok();
+        let icon = |color| {
+            base.as_deref()
+                .and_then(|base| to_icon(badged_rgba(base, color)))
+                .or_else(|| circle_icon(color))
+        };
+        Self {
+            connected: icon(CONNECTED_COLOR),
+            disconnected: icon(DISCONNECTED_COLOR),
+            radio_off: icon(RADIO_OFF_COLOR),
+            connecting: connecting_colors().map(icon).collect(),
+        }
+    }
+
+    pub fn for_state(&self, state: TrayState) -> Option<Icon> {
+        match state {
+            TrayState::Connected => self.connected.clone(),
+            TrayState::Disconnected => self.disconnected.clone(),
+            TrayState::RadioOff => self.radio_off.clone(),
+            TrayState::Connecting(frame) => self.connecting[frame % CONNECTING_FRAMES].clone(),
+        }
+    }
+}
+
+// Badge colors for each frame of the connecting animation
+fn connecting_colors() -> impl Iterator<Item = [u8; 4]> {
+    let half = CONNECTING_FRAMES / 2;
+    (0..CONNECTING_FRAMES).map(move |frame| {
+        let amount = 1.0 - frame.abs_diff(half) as f32 / half as f32;
+        let mut color = DISCONNECTED_COLOR;
+        for channel in 0..3 {
+            color[channel] = (DISCONNECTED_COLOR[channel] as f32 * (1.0 - amount)
+                + CONNECTING_COLOR[channel] as f32 * amount) as u8;
+        }
+        color
+    })
+}
+
+// Menu icons for each device class, plain and with the connected badge. Device items with
+// an icon can't show a checkmark, so the badge is what marks them connected.
+pub struct ClassIcons {
+    icons: Vec<(DeviceClass, MenuIcon, MenuIcon)>,
+}
+
+impl ClassIcons {
+    pub fn new() -> Self {
+        let mut icons = Vec::new();
+        for class in DeviceClass::ALL {
+            let png = match class {
+                DeviceClass::Audio => AUDIO_PNG,
+                DeviceClass::Input => INPUT_PNG,
+                DeviceClass::Phone => PHONE_PNG,
+                DeviceClass::Other => OTHER_PNG,
+            };
+            // Items without an icon still work, so a bad one only costs the glyph
+            let rgba = match decode_png(png) {
+                Ok(rgba) => rgba,
+                Err(e) => {
+                    warn!(error = %e, class = class.label(), "Failed to decode a device icon");
+                    continue;
+                }
+            };
+            let connected = badged_rgba(&rgba, CONNECTED_COLOR);
+            let icon = |rgba| {
+                MenuIcon::from_rgba(rgba, ICON_SIZE, ICON_SIZE)
+                    .inspect_err(|e| warn!(error = %e, class = class.label(), "Failed to create a device icon"))
+                    .ok()
+            };
+            let (Some(plain), Some(badged)) = (icon(rgba), icon(connected)) else {
+                continue;
+            };
+            icons.push((class, plain, badged));
+        }
+        Self { icons }
+    }
+
+    pub fn for_class(&self, class: DeviceClass, connected: bool) -> Option<MenuIcon> {
+        self.icons
+            .iter()
+            .find(|(icon_class, _, _)| *icon_class == class)
+            .map(|(_, plain, badged)| if connected { badged.clone() } else { plain.clone() })
+    }
+}
+
+// An embedded PNG as RGBA, scaled to tray size if it isn't already
+fn decode_png(png: &[u8]) -> Result<Vec<u8>, image::ImageError> {
+    let mut image = image::load_from_memory_with_format(png, image::ImageFormat::Png)?.into_rgba8();
+    if image.dimensions() != (ICON_SIZE, ICON_SIZE) {
+        image = image::imageops::resize(&image, ICON_SIZE, ICON_SIZE, image::imageops::FilterType::Lanczos3);
+    }
+    Ok(image.into_raw())
+}
+
+fn to_icon(rgba: Vec<u8>) -> Option<Icon> {
+    Icon::from_rgba(rgba, ICON_SIZE, ICON_SIZE)
+        .inspect_err(|e| warn!(error = %e, "Failed to create the tray icon"))
+        .ok()
+}
+
+// The base icon with a state-colored dot blended over its bottom-right corner
+fn badged_rgba(base: &[u8], color: [u8; 4]) -> Vec<u8> {
+    let center = ICON_SIZE as f32 - BADGE_RADIUS - 1.0;
+
+    let mut rgba = base.to_vec();
+    for y in 0..ICON_SIZE {
+        for x in 0..ICON_SIZE {
+            let distance = ((x as f32 - center).powi(2) + (y as f32 - center).powi(2)).sqrt();
+            let coverage = (BADGE_RADIUS - distance + 0.5).clamp(0.0, 1.0);
+            if coverage == 0.0 {
+                continue;
+            }
+            let pixel = &mut rgba[((y * ICON_SIZE + x) * 4) as usize..][..4];
+            for channel in 0..3 {
+                pixel[channel] = (pixel[channel] as f32 * (1.0 - coverage) + color[channel] as f32 * coverage) as u8;
+            }
+            pixel[3] = pixel[3].max((color[3] as f32 * coverage) as u8);
+        }
+    }
+    rgba
+}
+
+// A filled, anti-aliased circle so the state is readable at tray size
+fn circle_icon(color: [u8; 4]) -> Option<Icon> {
+    let center = (ICON_SIZE as f32 - 1.0) / 2.0;
+    let radius = ICON_SIZE as f32 / 2.0 - 1.0;
+
+    let mut rgba = Vec::with_capacity((ICON_SIZE * ICON_SIZE * 4) as usize);
+    for y in 0..ICON_SIZE {
+        for x in 0..ICON_SIZE {
+            let distance = ((x as f32 - center).powi(2) + (y as f32 - center).powi(2)).sqrt();
+            let coverage = (radius - distance + 0.5).clamp(0.0, 1.0);
+            rgba.extend_from_slice(&color[..3]);
+            rgba.push((color[3] as f32 * coverage) as u8);
+        }
+    }
+
+    to_icon(rgba)
+}
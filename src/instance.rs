@@ -0,0 +1,50 @@
+use tracing::warn;
+use windows::core::w;
+use windows::Win32::Foundation::{GetLastError, ERROR_ALREADY_EXISTS, WAIT_OBJECT_0};
+use windows::Win32::System::Threading::{CreateEventW, CreateMutexW, SetEvent, WaitForSingleObject, INFINITE};
+
+// Both names are per session, so another user signed in on the same machine gets their own tray
+const MUTEX_NAME: windows::core::PCWSTR = w!("Local\\bluetray-instance");
+// Set by a second launch to tell the running instance someone is looking for it
+const ACTIVATE_EVENT_NAME: windows::core::PCWSTR = w!("Local\\bluetray-activate");
+
+// Whether this is the only bluetray running. When another one already is, it gets told
+// about the launch and false comes back so this process can exit.
+pub fn claim() -> bool {
+    // The handle is left open on purpose; Windows releases the mutex when the process exits
+    let mutex = unsafe { CreateMutexW(None, true, MUTEX_NAME) };
+    if let Err(e) = mutex {
+        // Running twice beats not running at all
+        warn!(error = %e, "Failed to create the single-instance mutex");
+        return true;
+    }
+    if unsafe { GetLastError() } != ERROR_ALREADY_EXISTS {
+        return true;
+    }
+
+    match unsafe { CreateEventW(None, false, false, ACTIVATE_EVENT_NAME) } {
+        Ok(event) => {
+            if let Err(e) = unsafe { SetEvent(event) } {
+                warn!(error = %e, "Failed to signal the running instance");
+            }
+        }
+        Err(e) => warn!(error = %e, "Failed to open the running instance's activate event"),
+    }
+    false
+}
+
+// Calls on_activate on a background thread whenever a second launch is turned away
+pub fn listen_for_activation(on_activate: impl Fn() + Send + 'static) {
+    std::thread::spawn(move || {
+        let event = match unsafe { CreateEventW(None, false, false, ACTIVATE_EVENT_NAME) } {
+            Ok(event) => event,
+            Err(e) => {
+                warn!(error = %e, "Failed to create the activate event");
+                return;
+            }
+        };
+        while unsafe { WaitForSingleObject(event, INFINITE) } == WAIT_OBJECT_0 {
+            on_activate();
+        }
+    });
+}
@@ -0,0 +1,224 @@
+use std::io;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::windows::named_pipe::{ClientOptions, NamedPipeServer, ServerOptions};
+use tokio::sync::oneshot;
+use tracing::{debug, info, warn};
+use windows::Win32::Foundation::ERROR_PIPE_BUSY;
+
+use crate::bluetooth::DeviceKind;
+use crate::tasks;
+
+// How often, and how far apart, a client tries again when every pipe instance is busy
+const BUSY_ATTEMPTS: u32 = 10;
+const BUSY_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+// What a second bluetray, or a script, asks the running one to do. One JSON object per
+// line, e.g. {"command":"connect","device":"Headphones"} or {"command":"list"}.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "lowercase")]
+pub enum Request {
+    // By id, or by name ignoring case
+    Connect { device: String },
+    Disconnect { device: String },
+    List,
+    Refresh,
+}
+
+// The answer to one request, also one JSON object per line
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Response {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub devices: Option<Vec<DeviceListing>>,
+}
+
+// A paired device as returned by list and printed by --list
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DeviceListing {
+    pub id: String,
+    pub name: String,
+    pub kind: String,
+    pub class: String,
+    pub address: Option<String>,
+    pub connected: bool,
+    pub connectable: bool,
+    pub battery: Option<u8>,
+}
+
+// How listings name the kind of device
+pub fn kind_label(kind: DeviceKind) -> &'static str {
+    match kind {
+        DeviceKind::Classic => "classic",
+        DeviceKind::LowEnergy => "le",
+    }
+}
+
+// How a whole listing is written out as text
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ListingFormat {
+    // Aligned columns for reading
+    Table,
+    // A pretty-printed array for scripts
+    Json,
+}
+
+pub fn format_listing(listing: &[DeviceListing], format: ListingFormat) -> String {
+    match format {
+        ListingFormat::Table => {
+            let name_width = listing.iter().map(|device| device.name.chars().count()).max().unwrap_or(0).max(4);
+            let class_width = listing.iter().map(|device| device.class.len()).max().unwrap_or(0).max(5);
+            let mut table = format!(
+                "{:<name_width$}  {:<class_width$}  {:<9}  {:<7}  ID\n",
+                "NAME", "CLASS", "STATE", "BATTERY"
+            );
+            for device in listing {
+                let state = if device.connected { "connected" } else { "-" };
+                let battery = device.battery.map_or("-".to_string(), |level| format!("{}%", level));
+                table.push_str(&format!(
+                    "{:<name_width$}  {:<class_width$}  {:<9}  {:<7}  {}\n",
+                    device.name, device.class, state, battery, device.id
+                ));
+            }
+            table
+        }
+        // Only strings, numbers and bools, which always serialize
+        ListingFormat::Json => serde_json::to_string_pretty(listing).unwrap_or_default(),
+    }
+}
+
+impl Response {
+    pub fn ok(message: impl Into<String>) -> Self {
+        Self {
+            ok: true,
+            message: Some(message.into()),
+            devices: None,
+        }
+    }
+
+    pub fn error(message: impl Into<String>) -> Self {
+        Self {
+            ok: false,
+            message: Some(message.into()),
+            devices: None,
+        }
+    }
+
+    pub fn devices(devices: Vec<DeviceListing>) -> Self {
+        Self {
+            ok: true,
+            message: None,
+            devices: Some(devices),
+        }
+    }
+}
+
+// Per user, so two people signed in on one machine each talk to their own tray
+fn pipe_name() -> String {
+    let user = std::env::var("USERNAME").unwrap_or_default();
+    format!(r"\\.\pipe\bluetray-{}", user)
+}
+
+// Accepts clients until the process exits. Each request is handed to on_request along with
+// the sender for its answer, which may come back later, e.g. once a connect finishes.
+pub fn serve<F>(on_request: F)
+where
+    F: Fn(Request, oneshot::Sender<Response>) + Clone + Send + 'static,
+{
+    let name = pipe_name();
+    // Refuses to start if something else already owns the name
+    let mut server = match ServerOptions::new().first_pipe_instance(true).create(&name) {
+        Ok(server) => server,
+        Err(e) => {
+            warn!(pipe = %name, error = %e, "Failed to open the command pipe");
+            return;
+        }
+    };
+    info!(pipe = %name, "Listening for commands");
+
+    tasks::spawn_loop(async move {
+        loop {
+            if let Err(e) = server.connect().await {
+                debug!(error = %e, "Command pipe client went away before connecting");
+                continue;
+            }
+            // The next client needs an instance of its own to connect to
+            let client = server;
+            server = match ServerOptions::new().create(&name) {
+                Ok(server) => server,
+                Err(e) => {
+                    warn!(pipe = %name, error = %e, "Failed to reopen the command pipe");
+                    return;
+                }
+            };
+            tasks::spawn_loop(handle_client(client, on_request.clone()));
+        }
+    });
+}
+
+async fn handle_client<F>(pipe: NamedPipeServer, on_request: F)
+where
+    F: Fn(Request, oneshot::Sender<Response>),
+{
+    let (read, mut write) = tokio::io::split(pipe);
+    let mut lines = BufReader::new(read).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => {
+                debug!(?request, "Command received");
+                let (reply, answer) = oneshot::channel();
+                on_request(request, reply);
+                answer
+                    .await
+                    .unwrap_or_else(|_| Response::error("bluetray is shutting down"))
+            }
+            Err(e) => Response::error(format!("Malformed request: {}", e)),
+        };
+        let Ok(mut json) = serde_json::to_string(&response) else {
+            break;
+        };
+        json.push('\n');
+        if write.write_all(json.as_bytes()).await.is_err() {
+            break;
+        }
+    }
+}
+
+// Sends one request to the running bluetray and waits for the answer. None when there is
+// no running instance to talk to.
+pub async fn send(request: &Request) -> Option<Response> {
+    let name = pipe_name();
+    let mut attempts = 0;
+    let pipe = loop {
+        match ClientOptions::new().open(&name) {
+            Ok(pipe) => break pipe,
+            Err(e) if e.raw_os_error() == Some(ERROR_PIPE_BUSY.0 as i32) => {
+                attempts += 1;
+                if attempts == BUSY_ATTEMPTS {
+                    return Some(Response::error("The running bluetray is busy, try again"));
+                }
+                tokio::time::sleep(BUSY_RETRY_DELAY).await;
+            }
+            Err(_) => return None,
+        }
+    };
+
+    let result: io::Result<Response> = async {
+        let (read, mut write) = tokio::io::split(pipe);
+        let mut line = serde_json::to_string(request)?;
+        line.push('\n');
+        write.write_all(line.as_bytes()).await?;
+        let mut answer = String::new();
+        BufReader::new(read).read_line(&mut answer).await?;
+        Ok(serde_json::from_str(&answer)?)
+    }
+    .await;
+    Some(result.unwrap_or_else(|e| Response::error(format!("Lost contact with the running bluetray: {}", e))))
+}
@@ -0,0 +1,73 @@
+use windows::core::{Interface, Result, HSTRING, PCWSTR, PWSTR};
+use windows::Win32::Storage::EnhancedStorage::PKEY_Title;
+use windows::Win32::System::Com::StructuredStorage::PROPVARIANT;
+use windows::Win32::System::Com::{
+    CoCreateInstance, CoInitializeEx, CoUninitialize, CLSCTX_INPROC_SERVER, COINIT_APARTMENTTHREADED,
+};
+use windows::Win32::System::Variant::VT_LPWSTR;
+use windows::Win32::UI::Shell::Common::{IObjectArray, IObjectCollection};
+use windows::Win32::UI::Shell::PropertiesSystem::IPropertyStore;
+use windows::Win32::UI::Shell::{
+    DestinationList, EnumerableObjectCollection, ICustomDestinationList, IShellLinkW, ShellLink,
+};
+
+// A device offered in the jump list
+#[derive(Clone, Debug, PartialEq)]
+pub struct JumpDevice {
+    pub device_id: String,
+    pub name: String,
+}
+
+// Replaces the tasks in bluetray's taskbar jump list, seen with a right click once the exe
+// is pinned to the taskbar or Start, with a "Connect" entry per device. Each one runs
+// bluetray --connect <id>, which hands the connect to the running tray over the pipe.
+// WinRT's JumpList only works for packaged apps, so this goes through the shell's
+// ICustomDestinationList, which is what the taskbar reads for a plain exe. Blocking COM
+// calls, so run this off the async workers.
+pub fn set_devices(devices: &[JumpDevice]) -> Result<()> {
+    unsafe {
+        let initialized = CoInitializeEx(None, COINIT_APARTMENTTHREADED).is_ok();
+        let result = (|| {
+            let list: ICustomDestinationList = CoCreateInstance(&DestinationList, None, CLSCTX_INPROC_SERVER)?;
+            // An empty list can't be committed, so it's removed instead
+            if devices.is_empty() {
+                return list.DeleteList(PCWSTR::null());
+            }
+            let mut min_slots = 0;
+            // Items the user removed, which only matter for custom categories
+            let _removed: IObjectArray = list.BeginList(&mut min_slots)?;
+            let tasks: IObjectCollection = CoCreateInstance(&EnumerableObjectCollection, None, CLSCTX_INPROC_SERVER)?;
+            let exe = HSTRING::from(std::env::current_exe()?.as_path());
+            for device in devices {
+                tasks.AddObject(&connect_link(&exe, device)?)?;
+            }
+            list.AddUserTasks(&tasks)?;
+            list.CommitList()
+        })();
+        if initialized {
+            CoUninitialize();
+        }
+        result
+    }
+}
+
+fn connect_link(exe: &HSTRING, device: &JumpDevice) -> Result<IShellLinkW> {
+    unsafe {
+        let link: IShellLinkW = CoCreateInstance(&ShellLink, None, CLSCTX_INPROC_SERVER)?;
+        link.SetPath(exe)?;
+        link.SetArguments(&HSTRING::from(format!("--connect \"{}\"", device.device_id)))?;
+        link.SetIconLocation(exe, 0)?;
+        link.SetDescription(&HSTRING::from(format!("Connect to {}", device.name)))?;
+
+        // The entry shows the link's title. SetValue copies the string, so the value only
+        // borrows it and is never cleared.
+        let title = HSTRING::from(format!("Connect {}", device.name));
+        let mut value = PROPVARIANT::default();
+        (*value.Anonymous.Anonymous).vt = VT_LPWSTR;
+        (*value.Anonymous.Anonymous).Anonymous.pwszVal = PWSTR(title.as_ptr() as *mut u16);
+        let store: IPropertyStore = link.cast()?;
+        store.SetValue(&PKEY_Title, &value)?;
+        store.Commit()?;
+        Ok(link)
+    }
+}
@@ -0,0 +1,71 @@
+use std::io::Write;
+
+use tracing::{info, warn};
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::EnvFilter;
+use windows::System::Profile::AnalyticsInfo;
+
+use crate::state::app_data_dir;
+
+// Sets up logging to stdout and, when asked, to a file in %APPDATA%/bluetray/logs that
+// rolls over daily. RUST_LOG=bluetray=debug for the details; info and up otherwise.
+pub fn init(log_to_file: bool) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("bluetray=info"));
+
+    // Writes go straight to the file rather than through a background thread, so nothing
+    // is lost when the event loop exits the process
+    let appender = app_data_dir().filter(|_| log_to_file).map(|dir| {
+        RollingFileAppender::builder()
+            .rotation(Rotation::DAILY)
+            .filename_prefix("bluetray")
+            .filename_suffix("log")
+            .build(dir.join("logs"))
+    });
+    let (file, file_error) = match appender {
+        Some(Ok(file)) => (Some(file), None),
+        Some(Err(e)) => (None, Some(e)),
+        None => (None, None),
+    };
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer())
+        .with(file.map(|file| tracing_subscriber::fmt::layer().with_ansi(false).with_writer(file)))
+        .init();
+
+    info!(
+        version = env!("CARGO_PKG_VERSION"),
+        os = %os_version(),
+        arch = std::env::consts::ARCH,
+        "bluetray starting"
+    );
+    if let Some(e) = file_error {
+        warn!(error = %e, "Failed to open log file");
+    }
+}
+
+// Pushes out whatever stdout still holds. The log file needs nothing, since every line is
+// written to it straight away.
+pub fn flush() {
+    let _ = std::io::stdout().flush();
+}
+
+// Windows packs the four version parts into one number, 16 bits each
+pub fn os_version() -> String {
+    let version = AnalyticsInfo::VersionInfo()
+        .and_then(|info| info.DeviceFamilyVersion())
+        .ok()
+        .and_then(|version| version.to_string().parse::<u64>().ok());
+    match version {
+        Some(version) => format!(
+            "Windows {}.{}.{}.{}",
+            version >> 48,
+            (version >> 32) & 0xffff,
+            (version >> 16) & 0xffff,
+            version & 0xffff
+        ),
+        None => "Windows (unknown version)".to_string(),
+    }
+}
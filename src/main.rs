@@ -1,68 +1,206 @@
 #![cfg_attr(windows, windows_subsystem = "windows")]
 
+mod app;
+mod audio;
+mod autostart;
+mod bluetooth;
+mod cli;
+mod clipboard;
+mod config;
+mod connection;
+mod daemon;
+mod diagnostics;
+mod error;
+mod history;
+mod icons;
+mod instance;
+mod ipc;
+mod jumplist;
+mod logging;
+mod menu;
+mod notifications;
+mod pairing;
+mod platform;
+mod power;
+mod state;
+mod tasks;
+
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
-use std::vec;
+use std::time::{Duration, Instant, SystemTime};
 
+use global_hotkey::{hotkey::HotKey, GlobalHotKeyEvent, GlobalHotKeyManager, HotKeyState};
 use tao::{
     event::Event,
-    event_loop::{ControlFlow, EventLoopBuilder},
+    event_loop::{ControlFlow, EventLoopBuilder, EventLoopProxy},
+    platform::windows::{WindowBuilderExtWindows, WindowExtWindows},
+    window::WindowBuilder,
 };
+use tracing::{debug, error, info, info_span, warn, Instrument};
 use tray_icon::{
-    menu::{AboutMetadata, Menu, MenuEvent, MenuItem, PredefinedMenuItem},
-    Icon, TrayIconBuilder, TrayIconEvent,
+    menu::{AboutMetadata, CheckMenuItem, ContextMenu, Menu, MenuEvent, MenuItem, PredefinedMenuItem, Submenu},
+    MouseButton, MouseButtonState, TrayIconBuilder, TrayIconEvent,
 };
-use windows::{core::{Error, HSTRING}, Networking::Sockets::StreamSocket};
-use windows::Devices::Bluetooth::BluetoothDevice;
-use windows::Devices::Enumeration::DeviceInformation;
+use windows::core::{GUID, HSTRING};
+use windows::Networking::Sockets::StreamSocket;
+use windows::Storage::Streams::{DataReader, InputStreamOptions};
 
+use app::{AppState, MenuIds, ReconnectBatch, RequestOutcome, UiAction};
+use audio::AudioSwitcher;
+use bluetooth::{
+    adapter_radio, format_address, list_adapters, radio_is_on, set_radio_state, watch_paired_devices, watch_radio,
+    DeviceChange, DeviceClass, DeviceKind, PairedDevice,
+};
+use connection::{
+    connect_with_retry, contains_device_id, get_by_device_id, same_device_id, ConnectionManager, ConnectionState,
+    DeviceConnection, DeviceTest, StateChange,
+};
+use diagnostics::build_version;
+use error::BlueTrayError;
+use icons::{TrayIcons, TrayState, CONNECTING_FRAMES};
+use jumplist::JumpDevice;
+use history::{History, HistoryAction};
+use menu::{fill_history_menu, AdapterMenu, DeviceEntry, DeviceMenu, OrLog, PairMenu};
+use notifications::{ask, show_message_box, Notifier};
+use pairing::UnpairedDevice;
+use platform::{BluetoothPlatform, WindowsPlatform};
+use config::{Config, RetryPolicy};
+use state::SavedState;
+
+// Everything the event loop reacts to. Background tasks never touch the menu themselves;
+// they post one of these and the loop thread does the UI work.
 enum UserEvent {
     TrayIconEvent(tray_icon::TrayIconEvent),
     MenuEvent(tray_icon::menu::MenuEvent),
+    DevicesLoaded(Result<Vec<PairedDevice>, BlueTrayError>),
+    RadioChanged(bool),
+    RadioToggleFailed(BlueTrayError),
+    DeviceChanged(DeviceChange),
+    // A connect task finished. Carries the link itself, since the manager that stores it
+    // lives behind the loop's lock, along with how long the connect took. Storing it moves
+    // the device to its new state, which comes back as a ConnectionStateChanged.
+    ConnectionResult {
+        device_id: HSTRING,
+        result: Result<(DeviceConnection, Duration), BlueTrayError>,
+    },
+    // A connect attempt failed and another is coming; attempt is the one about to be made
+    ConnectRetrying {
+        device_id: HSTRING,
+        attempt: u32,
+        max_attempts: u32,
+    },
+    // The manager moved a device to another state
+    ConnectionStateChanged(StateChange),
+    ConnectionsLost(Vec<HSTRING>),
+    // A "Test connection" run finished
+    DeviceTested {
+        device_id: HSTRING,
+        name: String,
+        test: DeviceTest,
+    },
+    BatteryLevels(Vec<(HSTRING, Option<u8>)>),
+    SignalStrengths(Vec<(HSTRING, Option<i16>)>),
+    DataReceived {
+        device_id: HSTRING,
+        bytes: Vec<u8>,
+    },
+    Hotkey,
+    // Bluetray was launched again while this instance is running
+    AnotherInstanceStarted,
+    // A command from another bluetray over the pipe, answered through reply
+    IpcRequest {
+        request: ipc::Request,
+        reply: tokio::sync::oneshot::Sender<ipc::Response>,
+    },
+    // The machine woke up. Carries what was connected going to sleep; links that died since
+    // have already been reported as lost.
+    Resumed(Vec<String>),
+    // The config file was edited and parsed. Our own saves come through here too.
+    ConfigReloaded(Box<Config>),
+    // Try (again) to put the icon in the tray
+    CreateTrayIcon,
+    UnpairedDevicesFound(Result<Vec<UnpairedDevice>, BlueTrayError>),
+    PairingFinished {
+        device: UnpairedDevice,
+        result: Result<(), BlueTrayError>,
+    },
+    // The user confirmed forgetting this device
+    ForgetConfirmed(HSTRING),
+    Unpaired {
+        device_id: HSTRING,
+        result: Result<(), BlueTrayError>,
+    },
+    // A connected audio device was made the default output, replacing previous
+    AudioSwitched {
+        device_id: HSTRING,
+        endpoint: HSTRING,
+        previous: HSTRING,
+    },
 }
 
-// This struct will manage active Bluetooth connections
-struct ConnectionManager {
-    active_connections: HashMap<String, StreamSocket>,
-}
+// What the debug "Send test data" item writes to each connected device
+const TEST_PAYLOAD: &[u8] = b"bluetray test\r\n";
 
-impl ConnectionManager {
-    fn new() -> Self {
-        Self {
-            active_connections: HashMap::new(),
-        }
+// The tray may not be up yet, e.g. when started with Windows, so give it a few tries
+const TRAY_ICON_ATTEMPTS: u32 = 5;
+const TRAY_ICON_RETRY_DELAY: Duration = Duration::from_secs(3);
+
+// How long each frame of the connecting animation stays up
+const CONNECTING_FRAME_INTERVAL: Duration = Duration::from_millis(150);
+
+// How long quitting waits for connect attempts still in flight
+const SHUTDOWN_DEADLINE: Duration = Duration::from_secs(2);
+
+#[tokio::main]
+async fn main() {
+    // Command-line use skips the tray, and logging so stdout stays clean for scripts. It
+    // reads the config itself, reporting a broken file on the console.
+    if let Some(code) = cli::run().await {
+        std::process::exit(code);
     }
 
-    fn connect_device(&mut self, device_id: &HSTRING) -> Result<(), Error> {
-        let device_id_str = device_id.to_string();
-        
-        // Check if already connected
-        if self.active_connections.contains_key(&device_id_str) {
-            println!("Device already connected: {}", device_id_str);
-            return Ok(());
+    let daemon = cli::daemon_requested();
+    if daemon {
+        cli::attach_console();
+    }
+    // Loaded before logging since it decides where logs go
+    let (mut config, config_error) = Config::load();
+    logging::init(config.log_to_file);
+    if let Some(message) = config_error {
+        warn!(%message, "Ignoring malformed config file, using defaults");
+        // The tray has no console, so it says so in a dialog too
+        if !daemon {
+            show_message_box(
+                "Bluetooth Tray settings",
+                &format!("{}\n\nThe defaults are used instead.", message),
+            );
         }
-        
-        // Connect to the device
-        let socket = connect_to_bluetooth_device(device_id)?;
-        
-        // Store the connection
-        self.active_connections.insert(device_id_str, socket);
-        println!("Connection stored. Active connections: {}", self.active_connections.len());
-        
-        Ok(())
     }
 
-    fn disconnect_device(&mut self, device_id: &str) -> bool {
-        self.active_connections.remove(device_id).is_some()
+    // A second copy would race this one for the same sockets, so it hands over and leaves
+    if !instance::claim() {
+        info!("Bluetooth Tray is already running, exiting");
+        return;
+    }
+    let mut saved_state = SavedState::load();
+    if daemon {
+        daemon::run(config, saved_state).await;
+        return;
     }
-}
 
-#[tokio::main]
-async fn main() {
     let event_loop = EventLoopBuilder::<UserEvent>::with_user_event().build();
 
-    // Create connection manager
-    let connection_manager = Arc::new(Mutex::new(ConnectionManager::new()));
+    // Everything below talks to Bluetooth through this
+    let mut platform = WindowsPlatform::from_config(&config);
+    let mut connection_manager = ConnectionManager::new(platform);
+    connection_manager.set_flaky_after(config.flaky_after_failures);
+    connection_manager.set_max_concurrent_connects(config.max_concurrent_connects);
+    // The device items and the "Connected" toast follow the manager's state from here
+    let proxy = event_loop.create_proxy();
+    connection_manager.observe(move |change| {
+        let _ = proxy.send_event(UserEvent::ConnectionStateChanged(change.clone()));
+    });
+    let connection_manager = Arc::new(Mutex::new(connection_manager));
 
     // set a tray event handler that forwards the event and wakes up the event loop
     let proxy = event_loop.create_proxy();
@@ -76,115 +214,1447 @@ async fn main() {
         let _ = proxy.send_event(UserEvent::MenuEvent(event));
     }));
 
+    // Owns the menu when it's opened by something other than a right click on the icon
+    let menu_window = WindowBuilder::new()
+        .with_visible(false)
+        .with_skip_taskbar(true)
+        .build(&event_loop)
+        .inspect_err(|e| warn!(error = %e, "Failed to create the hidden menu window"))
+        .ok();
+
+    let proxy = event_loop.create_proxy();
+    instance::listen_for_activation(move || {
+        let _ = proxy.send_event(UserEvent::AnotherInstanceStarted);
+    });
+    // Commands like bluetray --connect come in here, so the connection stays with this instance
+    let proxy = event_loop.create_proxy();
+    ipc::serve(move |request, reply| {
+        let _ = proxy.send_event(UserEvent::IpcRequest { request, reply });
+    });
+
+    // The hotkey toggles the favorite device from anywhere. The manager has to stay alive
+    // and on this thread for the hotkey to stay registered.
+    let _hotkey_manager = register_hotkey(&config.hotkey, event_loop.create_proxy());
+
+    let proxy = event_loop.create_proxy();
+    let _config_watcher = config::watch(move |config| {
+        let _ = proxy.send_event(UserEvent::ConfigReloaded(Box::new(config)));
+    });
+
+    // Follow the radio so the menu can react to Bluetooth being switched on and off. The
+    // radio has to outlive the loop for the handler to keep firing. It belongs to the adapter
+    // picked at startup; switching adapters only changes which devices are listed until
+    // the next start.
+    let radio = adapter_radio(platform.adapter).await;
+    let mut radio_on = radio.as_ref().is_none_or(radio_is_on);
+    if let Some(radio) = &radio {
+        let proxy = event_loop.create_proxy();
+        if let Err(e) = watch_radio(radio, move |on| {
+            let _ = proxy.send_event(UserEvent::RadioChanged(on));
+        }) {
+            warn!(error = %e, "Failed to watch Bluetooth radio");
+        }
+    }
+
     let tray_menu = Menu::new();
+    let refresh_i = MenuItem::new("Refresh devices", true, None);
+    let disconnect_all_i = MenuItem::new("Disconnect all", true, None);
+    let reconnect_all_i = MenuItem::new("Reconnect all", true, None);
+    let radio_i = MenuItem::new(radio_toggle_label(radio_on), radio.is_some(), None);
+    let notifications_i = CheckMenuItem::new("Notifications", true, config.notifications, None);
+    let auto_reconnect_i = CheckMenuItem::new("Reconnect on startup", true, config.auto_reconnect, None);
+    let autostart_i = CheckMenuItem::new("Start with Windows", true, autostart::is_enabled(), None);
+    let switch_audio_i = CheckMenuItem::new("Switch audio output on connect", true, config.switch_audio_default, None);
+    // The toggles most people want, so they never need to open the config file
+    let settings_menu = Submenu::new("Settings", true);
+    settings_menu
+        .append_items(&[&auto_reconnect_i, &notifications_i, &autostart_i, &switch_audio_i])
+        .or_log();
+    let report_i = MenuItem::new("Copy connection info", true, None);
+    let diagnostics_i = MenuItem::new("Copy diagnostics", true, None);
+    let send_test_i = MenuItem::new("Send test data", true, None);
     let quit_i = MenuItem::new("Quit", true, None);
 
-    // Get Bluetooth devices
-    let bluetooth_devices = get_paired_bluetooth_devices().await.unwrap();
-
-    // Store device info mapped to menu items
-    let mut device_map = HashMap::new();
-    let device_items: Vec<MenuItem> = bluetooth_devices
-        .iter()
-        .map(|device_info| {
-            let item = MenuItem::new(
-                device_info
-                    .Name()
-                    .expect("device name doesn't exist")
-                    .to_string(),
-                true,
-                None,
-            );
-            device_map.insert(item.id().clone(), device_info.Id().unwrap());
-            item
-        })
-        .collect();
-
     tray_menu.append_items(&[
         &PredefinedMenuItem::about(
             None,
             Some(AboutMetadata {
                 name: Some("Bluetooth Tray".to_string()),
+                version: Some(build_version()),
+                authors: Some(env!("CARGO_PKG_AUTHORS").split(':').map(str::to_string).collect()),
                 copyright: Some("Copyright bluetray".to_string()),
+                website: Some(env!("CARGO_PKG_REPOSITORY").to_string()),
+                website_label: Some("Source and issues".to_string()),
                 ..Default::default()
             }),
         ),
         &PredefinedMenuItem::separator(),
-    ]).unwrap();
+    ]).or_log();
+
+    let mut device_menu = DeviceMenu::new(tray_menu.clone());
+    let mut history = History::load();
+    let history_menu = Submenu::new("History", true);
+    fill_history_menu(&history_menu, &history);
+    let mut pair_menu = PairMenu::new();
+    let mut adapter_menu = AdapterMenu::new(platform.adapter);
+    adapter_menu.show_adapters(list_adapters().await);
 
-    // Add device menu items
-    for item in &device_items {
-        tray_menu.append(item).unwrap();
+    tray_menu.append(&PredefinedMenuItem::separator()).or_log();
+    tray_menu.append(&refresh_i).or_log();
+    tray_menu.append(pair_menu.menu()).or_log();
+    tray_menu.append(&disconnect_all_i).or_log();
+    tray_menu.append(&reconnect_all_i).or_log();
+    tray_menu.append(device_menu.copy_menu()).or_log();
+    tray_menu.append(device_menu.pin_menu()).or_log();
+    tray_menu.append(device_menu.cancel_menu()).or_log();
+    tray_menu.append(device_menu.stop_retry_menu()).or_log();
+    tray_menu.append(device_menu.test_menu()).or_log();
+    tray_menu.append(device_menu.forget_menu()).or_log();
+    tray_menu.append(device_menu.profile_menu()).or_log();
+    tray_menu.append(&history_menu).or_log();
+    tray_menu.append(&radio_i).or_log();
+    tray_menu.append(adapter_menu.menu()).or_log();
+    settings_menu.append(device_menu.auto_connect_menu()).or_log();
+    tray_menu.append(&settings_menu).or_log();
+    tray_menu.append(&report_i).or_log();
+    tray_menu.append(&diagnostics_i).or_log();
+    // Only in debug builds, for poking at serial-style devices
+    if cfg!(debug_assertions) {
+        tray_menu.append(&send_test_i).or_log();
     }
+    tray_menu.append(&quit_i).or_log();
+    let menu_ids = MenuIds {
+        quit: quit_i.id().clone(),
+        disconnect_all: disconnect_all_i.id().clone(),
+    };
+
+    // Enumerating can take seconds with many paired devices, so the tray comes up with a
+    // placeholder and the devices fill in once DevicesLoaded arrives
+    device_menu.set_aliases(config.aliases.clone());
+    device_menu.set_connect_profiles(config.connect_profiles.clone());
+    device_menu.set_layout(config.device_order, config.group_by_class, config.connected_first);
+    device_menu.set_pinned(config.pinned_devices.clone());
+    device_menu.set_auto_connect(config.auto_connect_devices.clone());
+    device_menu.show_loading();
+    spawn_refresh(&event_loop.create_proxy(), platform);
+    // Bring back whatever was connected last time, once there are devices to connect to
+    let mut reconnect_on_load = config.auto_reconnect;
+
+    // Keep the menu in sync as devices get paired and unpaired
+    let proxy = event_loop.create_proxy();
+    let _device_watchers = watch_paired_devices(move |change| {
+        let _ = proxy.send_event(UserEvent::DeviceChanged(change));
+    })
+    .unwrap_or_else(|e| {
+        warn!(error = %e, "Failed to watch for paired devices");
+        Vec::new()
+    });
+
+    // Sending a new interval here reschedules the health check without a restart
+    let (health_check_interval, interval_changes) = tokio::sync::watch::channel(config.health_check_interval());
+    spawn_health_check(
+        connection_manager.clone(),
+        event_loop.create_proxy(),
+        interval_changes,
+        config.keepalive.clone(),
+    );
 
-    tray_menu.append(&PredefinedMenuItem::separator()).unwrap();
-    tray_menu.append(&quit_i).unwrap();
+    // Sleep kills RFCOMM links without the manager noticing, so check them all on wake
+    let proxy = event_loop.create_proxy();
+    let manager = connection_manager.clone();
+    let resume_runtime = tokio::runtime::Handle::current();
+    if let Err(e) = power::watch_resume(move || {
+        info!("Woke up from sleep");
+        resume_runtime.spawn(check_after_resume(manager.clone(), proxy.clone()));
+    }) {
+        warn!(error = %e, "Failed to watch for resume from sleep");
+    }
 
     let mut tray_icon = None;
+    let mut tray_icon_attempts = 0;
+    let mut audio_switcher = AudioSwitcher::default();
+    let icons = TrayIcons::new();
+    let mut showing_state = TrayState::Disconnected;
+    // When the connecting animation started, while any connect is in flight
+    let mut connecting_since: Option<Instant> = None;
+    let mut showing_tooltip = connection_tooltip(&[]);
+    // What the taskbar jump list was last set to
+    let mut jump_list_shown: Option<Vec<JumpDevice>> = None;
 
     let connection_manager_clone = connection_manager.clone();
-    
+    let proxy = event_loop.create_proxy();
+    let mut notifier = Notifier::new();
+    notifier.enabled = config.notifications;
+    // What "Reconnect all" started and is still waiting on
+    let mut reconnect_batch: Option<ReconnectBatch> = None;
+    // Pipe clients waiting for a connect to finish, by device id
+    let mut pending_connects: HashMap<String, Vec<tokio::sync::oneshot::Sender<ipc::Response>>> = HashMap::new();
+    // When each device was last connected for coming into range, for the cooldown
+    let mut auto_connect_attempts: HashMap<String, Instant> = HashMap::new();
+
     event_loop.run(move |event, _, control_flow| {
         *control_flow = ControlFlow::Wait;
 
         match event {
             Event::NewEvents(tao::event::StartCause::Init) => {
-                let icon = Icon::from_rgba(vec![0, 0, 200, 255], 1, 1).unwrap();
-
                 // We create the icon once the event loop is actually running
                 // to prevent issues like https://github.com/tauri-apps/tray-icon/issues/90
-                tray_icon = Some(
-                    TrayIconBuilder::new()
-                        .with_menu(Box::new(tray_menu.clone()))
-                        .with_tooltip("Bluetray")
-                        .with_icon(icon)
-                        .build()
-                        .unwrap(),
-                );
+                let _ = proxy.send_event(UserEvent::CreateTrayIcon);
+            }
+
+            Event::UserEvent(UserEvent::CreateTrayIcon) => {
+                let mut builder = TrayIconBuilder::new()
+                    .with_menu(Box::new(tray_menu.clone()))
+                    .with_tooltip(&showing_tooltip)
+                    // Left click reconnects the last device; the menu is on right click
+                    .with_menu_on_left_click(false);
+                if let Some(icon) = icons.for_state(showing_state) {
+                    builder = builder.with_icon(icon);
+                }
+                let built = builder.build();
+                tray_icon_attempts += 1;
+                match built {
+                    Ok(icon) => tray_icon = Some(icon),
+                    Err(e) if tray_icon_attempts < TRAY_ICON_ATTEMPTS => {
+                        warn!(error = %e, attempt = tray_icon_attempts, "Failed to create tray icon, retrying");
+                        let proxy = proxy.clone();
+                        tasks::spawn(async move {
+                            tokio::time::sleep(TRAY_ICON_RETRY_DELAY).await;
+                            let _ = proxy.send_event(UserEvent::CreateTrayIcon);
+                        });
+                    }
+                    // Without an icon there's no way to use or even quit the app
+                    Err(e) => {
+                        error!(error = %e, "Failed to create tray icon, exiting");
+                        let _ = show_message_box(
+                            "Bluetooth Tray",
+                            &format!("Could not add the icon to the system tray, so Bluetooth Tray is closing.\n\n{}", e),
+                        )
+                        .join();
+                        shutdown(&connection_manager_clone, &saved_state);
+                        *control_flow = ControlFlow::Exit;
+                    }
+                }
             }
 
             Event::UserEvent(UserEvent::TrayIconEvent(event)) => {
-                println!("{event:?}");
+                debug!(?event, "Tray icon event");
+
+                // The pointer has to pass over the icon before any click, so refreshing here
+                // keeps the connected-for times current whenever the menu opens
+                let manager = connection_manager_clone.lock().unwrap();
+                for connection in manager.list_connections() {
+                    if let Some(entry) = device_menu.find_mut(&HSTRING::from(&connection.device_id)) {
+                        entry.set_connected_for(connection.connected_since.map(|since| since.elapsed()));
+                    }
+                }
+                drop(manager);
+                device_menu.refresh_last_seen();
+
+                if let TrayIconEvent::Click {
+                    button: MouseButton::Left,
+                    button_state: MouseButtonState::Up,
+                    ..
+                } = event
+                {
+                    let last_device = saved_state.last_device.clone().map(HSTRING::from);
+                    match last_device.as_ref().and_then(|device_id| device_menu.find_mut(device_id)) {
+                        Some(entry) => {
+                            let mut manager = connection_manager_clone.lock().unwrap();
+                            if !start_connect(&proxy, &mut manager, entry, config.retry) {
+                                debug!(device_id = %entry.device_id, "Last device already connected or connecting");
+                            }
+                        }
+                        None => notifier.info(
+                            "No recent device",
+                            "Connect a device from the menu first; right-click the tray icon to open it",
+                        ),
+                    }
+                }
+
+                // Middle click flips the quick toggle device without going through the menu
+                if let TrayIconEvent::Click {
+                    button: MouseButton::Middle,
+                    button_state: MouseButtonState::Up,
+                    ..
+                } = event
+                {
+                    let target = config
+                        .quick_toggle_device
+                        .clone()
+                        .or_else(|| config.favorite_device.clone())
+                        .map(HSTRING::from);
+                    match target.as_ref().and_then(|device_id| device_menu.find_mut(device_id)) {
+                        Some(entry) => {
+                            let mut manager = connection_manager_clone.lock().unwrap();
+                            let mut actions = Vec::new();
+                            app::toggle_device(&mut manager, entry, config.retry, &mut actions);
+                            apply_actions(
+                                actions,
+                                &proxy,
+                                &mut manager,
+                                &notifier,
+                                &mut saved_state,
+                                &mut history,
+                                &history_menu,
+                            );
+                        }
+                        None => {
+                            if let Some(window) = &menu_window {
+                                unsafe {
+                                    tray_menu.show_context_menu_for_hwnd(window.hwnd(), None);
+                                }
+                            }
+                        }
+                    }
+                }
             }
 
             Event::UserEvent(UserEvent::MenuEvent(event)) => {
-                println!("{event:?}");
+                debug!(?event, "Menu event");
+
+                // Quit, "Disconnect all" and the devices themselves; everything else is below
+                let mut manager = connection_manager_clone.lock().unwrap();
+                let mut state = AppState {
+                    device_menu: &mut device_menu,
+                    manager: &mut manager,
+                    retry: config.retry,
+                };
+                let handled = app::handle_menu_event(&event.id, &menu_ids, &mut state).map(|actions| {
+                    apply_actions(
+                        actions,
+                        &proxy,
+                        &mut manager,
+                        &notifier,
+                        &mut saved_state,
+                        &mut history,
+                        &history_menu,
+                    )
+                });
+                drop(manager);
 
-                if event.id == quit_i.id() {
-                    tray_icon.take();
-                    *control_flow = ControlFlow::Exit;
-                } else if let Some(device_id) = device_map.get(&event.id) {
-                    // Use connection manager to connect to the device
+                if let Some(quit) = handled {
+                    if quit {
+                        tray_icon.take();
+                        shutdown(&connection_manager_clone, &saved_state);
+                        *control_flow = ControlFlow::Exit;
+                    }
+                } else if event.id == reconnect_all_i.id() {
+                    // What was connected at the last save, plus what's meant to stay connected
+                    let mut candidates = saved_state.connected_devices.clone();
+                    candidates.extend(config.reconnect_when_lost.iter().cloned());
                     let mut manager = connection_manager_clone.lock().unwrap();
-                    if let Err(e) = manager.connect_device(device_id) {
-                        println!("Failed to connect to device: {}", e);
+                    let mut state = AppState {
+                        device_menu: &mut device_menu,
+                        manager: &mut manager,
+                        retry: config.retry,
+                    };
+                    let (actions, started) = app::reconnect_all(&mut state, &candidates);
+                    info!(devices = started.len(), "Reconnecting all");
+                    if !started.is_empty() {
+                        reconnect_batch.get_or_insert_default().add(started);
+                    }
+                    apply_actions(
+                        actions,
+                        &proxy,
+                        &mut manager,
+                        &notifier,
+                        &mut saved_state,
+                        &mut history,
+                        &history_menu,
+                    );
+                } else if event.id == notifications_i.id() {
+                    notifier.enabled = notifications_i.is_checked();
+                    config.notifications = notifier.enabled;
+                    config.save();
+                } else if event.id == auto_reconnect_i.id() {
+                    config.auto_reconnect = auto_reconnect_i.is_checked();
+                    config.save();
+                } else if event.id == autostart_i.id() {
+                    if let Err(e) = autostart::set_enabled(autostart_i.is_checked()) {
+                        error!(error = %e, "Failed to update the Run registry key");
+                        notifier.error("Could not change Start with Windows", &e.to_string());
+                    }
+                    autostart_i.set_checked(autostart::is_enabled());
+                } else if event.id == switch_audio_i.id() {
+                    config.switch_audio_default = switch_audio_i.is_checked();
+                    config.save();
+                } else if event.id == report_i.id() {
+                    let report = connection_manager_clone.lock().unwrap().report();
+                    info!("Connection info:\n{}", report);
+                    match clipboard::copy_text(&report) {
+                        Ok(()) => notifier.info("Copied", "Copied the connection info to the clipboard"),
+                        Err(e) => {
+                            warn!(error = %e, "Failed to copy to the clipboard");
+                            notifier.error("Could not copy", &e.to_string());
+                        }
+                    }
+                } else if event.id == diagnostics_i.id() {
+                    let manager = connection_manager_clone.lock().unwrap();
+                    let report = diagnostics::collect(&device_menu, &manager, config.redact_diagnostics);
+                    drop(manager);
+                    match clipboard::copy_text(&report) {
+                        Ok(()) => notifier.info("Copied", "Copied the diagnostics to the clipboard"),
+                        Err(e) => {
+                            warn!(error = %e, "Failed to copy to the clipboard");
+                            notifier.error("Could not copy", &e.to_string());
+                        }
+                    }
+                } else if event.id == send_test_i.id() {
+                    let locked = connection_manager_clone.lock().unwrap();
+                    let writes: Vec<_> = locked
+                        .connected_ids()
+                        .into_iter()
+                        .map(|device_id| {
+                            let write = locked.send(&device_id, TEST_PAYLOAD);
+                            (device_id, write)
+                        })
+                        .collect();
+                    drop(locked);
+                    let manager = connection_manager_clone.clone();
+                    tasks::spawn(async move {
+                        for (device_id, write) in writes {
+                            match write.await {
+                                Ok(()) => {
+                                    manager.lock().unwrap().record_sent(&device_id, TEST_PAYLOAD.len());
+                                    info!(%device_id, bytes = TEST_PAYLOAD.len(), "Sent test data");
+                                }
+                                Err(e) => warn!(%device_id, error = %e, "Failed to send test data"),
+                            }
+                        }
+                    });
+                } else if event.id == radio_i.id() {
+                    if let Some(radio) = &radio {
+                        let radio = radio.clone();
+                        let proxy = proxy.clone();
+                        let turn_on = !radio_on;
+                        tasks::spawn(async move {
+                            let event = match set_radio_state(&radio, turn_on).await {
+                                Ok(()) => UserEvent::RadioChanged(turn_on),
+                                Err(e) => UserEvent::RadioToggleFailed(e),
+                            };
+                            let _ = proxy.send_event(event);
+                        });
+                    }
+                } else if device_menu.is_retry(&event.id) {
+                    device_menu.show_loading();
+                    spawn_refresh(&proxy, platform);
+                } else if event.id == refresh_i.id() {
+                    // In case the watcher missed an edit
+                    match Config::reload() {
+                        Ok(reloaded) => {
+                            let _ = proxy.send_event(UserEvent::ConfigReloaded(Box::new(reloaded)));
+                        }
+                        Err(e) => {
+                            warn!(error = %e, "Config file doesn't parse, keeping the current settings");
+                            notifier.error("Settings not reloaded", &e.to_string());
+                        }
+                    }
+                    spawn_refresh(&proxy, platform);
+                } else if let Some(format) = device_menu.copy_all_target(&event.id) {
+                    let listing = app::device_listing(&device_menu, &connection_manager_clone.lock().unwrap());
+                    match clipboard::copy_text(&ipc::format_listing(&listing, format)) {
+                        Ok(()) => {
+                            notifier.info("Copied", &format!("Copied {} device(s) to the clipboard", listing.len()))
+                        }
+                        Err(e) => {
+                            warn!(error = %e, "Failed to copy to the clipboard");
+                            notifier.error("Could not copy", &e.to_string());
+                        }
+                    }
+                } else if let Some((text, description)) = device_menu.copy_target(&event.id) {
+                    match clipboard::copy_text(&text) {
+                        Ok(()) => notifier.info("Copied", &format!("Copied the {} to the clipboard", description)),
+                        Err(e) => {
+                            warn!(error = %e, "Failed to copy to the clipboard");
+                            notifier.error("Could not copy", &e.to_string());
+                        }
+                    }
+                } else if let Some(device_id) = device_menu.pin_target(&event.id) {
+                    if let Some(index) = config.pinned_devices.iter().position(|id| same_device_id(id, &device_id)) {
+                        config.pinned_devices.remove(index);
+                    } else {
+                        config.pinned_devices.push(device_id);
+                    }
+                    config.save();
+                    device_menu.set_pinned(config.pinned_devices.clone());
+                } else if let Some(device_id) = device_menu.auto_connect_target(&event.id) {
+                    let position = config.auto_connect_devices.iter().position(|id| same_device_id(id, &device_id));
+                    if let Some(index) = position {
+                        config.auto_connect_devices.remove(index);
+                    } else {
+                        config.auto_connect_devices.push(device_id);
+                    }
+                    config.save();
+                    device_menu.set_auto_connect(config.auto_connect_devices.clone());
+                } else if let Some(adapter) = adapter_menu.target(&event.id) {
+                    config.adapter = adapter.map(format_address);
+                    config.save();
+                    adapter_menu.set_selected(adapter);
+                    platform.adapter = adapter;
+                    *connection_manager_clone.lock().unwrap().platform_mut() = platform;
+                    // Devices on the other adapter drop out of the menu, disconnected
+                    spawn_refresh(&proxy, platform);
+                } else if pair_menu.is_scan(&event.id) {
+                    pair_menu.set_scanning();
+                    let proxy = proxy.clone();
+                    tasks::spawn(async move {
+                        let devices = pairing::find_unpaired_devices().await;
+                        let _ = proxy.send_event(UserEvent::UnpairedDevicesFound(devices));
+                    });
+                } else if let Some(device) = pair_menu.start_pairing(&event.id) {
+                    let proxy = proxy.clone();
+                    tasks::spawn(async move {
+                        let result = pairing::pair_device(&device).await;
+                        let _ = proxy.send_event(UserEvent::PairingFinished { device, result });
+                    });
+                } else if let Some(device_id) = device_menu.cancel_target(&event.id) {
+                    // The task is gone and won't report, so report for it; the result arm
+                    // puts the item and anyone waiting on the connect right
+                    if connection_manager_clone.lock().unwrap().cancel_connect(&device_id) {
+                        let _ = proxy.send_event(UserEvent::ConnectionResult {
+                            device_id: HSTRING::from(&device_id),
+                            result: Err(BlueTrayError::Cancelled),
+                        });
+                    }
+                } else if let Some(device_id) = device_menu.stop_retry_target(&event.id) {
+                    // The attempt underway finishes first, and its result ends the connect
+                    if connection_manager_clone.lock().unwrap().stop_retrying(&device_id) {
+                        info!(%device_id, "Asked to stop retrying");
+                    }
+                } else if let Some(entry) = device_menu.test_target(&event.id) {
+                    let device_id = entry.device_id.clone();
+                    let name = entry.name.clone();
+                    // Devices that take a keepalive can take it as test data too
+                    let keepalive = get_by_device_id(&config.keepalive, &device_id.to_string());
+                    let probe = keepalive.map(|text| text.as_bytes().to_vec());
+                    let manager = connection_manager_clone.lock().unwrap();
+                    let test = manager.test_device(&device_id, entry.kind, entry.preferred_service, probe);
+                    let slots = manager.connect_slots();
+                    drop(manager);
+                    let proxy = proxy.clone();
+                    let span = info_span!("test", %device_id);
+                    tasks::spawn(
+                        async move {
+                            let _slot = slots.acquire_owned().await;
+                            let test = test.await;
+                            let _ = proxy.send_event(UserEvent::DeviceTested { device_id, name, test });
+                        }
+                        .instrument(span),
+                    );
+                } else if let Some((device_id, name)) = device_menu.forget_target(&event.id) {
+                    // Asking blocks until answered, so keep it off the event loop
+                    let proxy = proxy.clone();
+                    tasks::spawn_blocking(move || {
+                        let message = format!("Remove {} from this PC? It will have to be paired again to use it.", name);
+                        if ask("Forget device", &message) {
+                            let _ = proxy.send_event(UserEvent::ForgetConfirmed(device_id));
+                        }
+                    });
+                } else if let Some((device_id, uuid)) = device_menu.profile_target(&event.id) {
+                    // Drops the old entry even when the file spells the id differently
+                    config.connect_profiles.retain(|id, _| !same_device_id(id, &device_id));
+                    if let Some(uuid) = uuid {
+                        config.connect_profiles.insert(device_id, format!("{:?}", uuid));
+                    }
+                    config.save();
+                    // Also puts the checkmark back on the one choice
+                    device_menu.set_connect_profiles(config.connect_profiles.clone());
+                }
+            }
+
+            Event::UserEvent(UserEvent::Hotkey) => {
+                let favorite = config.favorite_device.clone().map(HSTRING::from);
+                match favorite.as_ref().and_then(|device_id| device_menu.find_mut(device_id)) {
+                    Some(entry) => {
+                        let mut manager = connection_manager_clone.lock().unwrap();
+                        let mut actions = Vec::new();
+                        app::toggle_device(&mut manager, entry, config.retry, &mut actions);
+                        apply_actions(
+                            actions,
+                            &proxy,
+                            &mut manager,
+                            &notifier,
+                            &mut saved_state,
+                            &mut history,
+                            &history_menu,
+                        );
+                    }
+                    None => notifier.info(
+                        "No favorite device",
+                        "Set favorite_device in the config file to the id of the device the hotkey should toggle",
+                    ),
+                }
+            }
+
+            // tray-icon can't open the menu on request, so point the user at the icon instead.
+            // A box rather than a toast, since they just asked for the app and expect to see it.
+            Event::UserEvent(UserEvent::AnotherInstanceStarted) => {
+                let _ = show_message_box(
+                    "Bluetooth Tray is already running",
+                    "Right-click its icon in the system tray to open the menu.",
+                );
+            }
+
+            Event::UserEvent(UserEvent::Resumed(connected)) if config.reconnect_on_resume => {
+                let mut candidates = connected;
+                candidates.extend(config.reconnect_when_lost.iter().cloned());
+                let mut manager = connection_manager_clone.lock().unwrap();
+                let mut state = AppState {
+                    device_menu: &mut device_menu,
+                    manager: &mut manager,
+                    retry: config.retry,
+                };
+                // Quietly, unlike the menu item, when nothing needed doing
+                let (actions, started) = app::reconnect_all(&mut state, &candidates);
+                if !started.is_empty() {
+                    info!(devices = started.len(), "Reconnecting after sleep");
+                    reconnect_batch.get_or_insert_default().add(started);
+                    apply_actions(
+                        actions,
+                        &proxy,
+                        &mut manager,
+                        &notifier,
+                        &mut saved_state,
+                        &mut history,
+                        &history_menu,
+                    );
+                }
+            }
+
+            Event::UserEvent(UserEvent::IpcRequest { request, reply }) => {
+                let mut manager = connection_manager_clone.lock().unwrap();
+                let mut state = AppState {
+                    device_menu: &mut device_menu,
+                    manager: &mut manager,
+                    retry: config.retry,
+                };
+                let mut actions = Vec::new();
+                match app::handle_request(request, &mut state, &mut actions) {
+                    RequestOutcome::Reply(response) => {
+                        let _ = reply.send(response);
+                    }
+                    RequestOutcome::AwaitConnect(device_id) => {
+                        pending_connects.entry(device_id.to_string()).or_default().push(reply);
+                    }
+                }
+                apply_actions(actions, &proxy, &mut manager, &notifier, &mut saved_state, &mut history, &history_menu);
+            }
+
+            // The hotkey, log file and keepalives only change on restart
+            Event::UserEvent(UserEvent::ConfigReloaded(reloaded)) if *reloaded != config => {
+                info!("Config file changed, applying");
+                config = *reloaded;
+                notifier.enabled = config.notifications;
+                notifications_i.set_checked(config.notifications);
+                auto_reconnect_i.set_checked(config.auto_reconnect);
+                switch_audio_i.set_checked(config.switch_audio_default);
+                device_menu.set_aliases(config.aliases.clone());
+                device_menu.set_connect_profiles(config.connect_profiles.clone());
+                device_menu.set_layout(config.device_order, config.group_by_class, config.connected_first);
+                device_menu.set_pinned(config.pinned_devices.clone());
+                device_menu.set_auto_connect(config.auto_connect_devices.clone());
+
+                platform = WindowsPlatform::from_config(&config);
+                adapter_menu.set_selected(platform.adapter);
+                let mut manager = connection_manager_clone.lock().unwrap();
+                *manager.platform_mut() = platform;
+                manager.set_flaky_after(config.flaky_after_failures);
+                manager.set_max_concurrent_connects(config.max_concurrent_connects);
+                drop(manager);
+                let _ = health_check_interval.send(config.health_check_interval());
+
+                // Aliases and flaky marks are applied to the items on the next sync
+                spawn_refresh(&proxy, platform);
+            }
+
+            Event::UserEvent(UserEvent::DevicesLoaded(devices)) => {
+                radio_on = !matches!(devices, Err(BlueTrayError::RadioOff));
+                let mut manager = connection_manager_clone.lock().unwrap();
+                let loaded = devices.is_ok();
+                device_menu.sync(devices, &mut manager);
+                device_menu.show_last_seen(&saved_state);
+                if loaded && reconnect_on_load {
+                    // Nothing is connected yet, so the saved list stays until results come in
+                    reconnect_on_load = false;
+                    for device_id in &saved_state.connected_devices {
+                        if let Some(entry) = device_menu.find_mut(&HSTRING::from(device_id)) {
+                            start_connect(&proxy, &mut manager, entry, config.retry);
+                        }
+                    }
+                } else {
+                    saved_state.set_connected_devices(manager.connected_ids());
+                }
+                // Forget pins for devices that were unpaired
+                if device_menu.pinned() != config.pinned_devices {
+                    config.pinned_devices = device_menu.pinned().to_vec();
+                    config.save();
+                }
+            }
+
+            Event::UserEvent(UserEvent::RadioChanged(on)) if on != radio_on => {
+                info!(on, "Bluetooth radio switched");
+                radio_on = on;
+                if on {
+                    spawn_refresh(&proxy, platform);
+                } else {
+                    let mut manager = connection_manager_clone.lock().unwrap();
+                    device_menu.sync(Err(BlueTrayError::RadioOff), &mut manager);
+                }
+            }
+
+            Event::UserEvent(UserEvent::DeviceChanged(change)) => match change {
+                // A new device needs probing before it can be shown, which the refresh does
+                DeviceChange::Added => spawn_refresh(&proxy, platform),
+                DeviceChange::Removed(device_id) => {
+                    info!(%device_id, "Device unpaired");
+                    let mut manager = connection_manager_clone.lock().unwrap();
+                    device_menu.remove_device(&device_id, &mut manager);
+                    saved_state.set_connected_devices(manager.connected_ids());
+                }
+                DeviceChange::Seen(device_id) => {
+                    let now = SystemTime::now();
+                    saved_state.set_last_seen(&device_id.to_string(), now);
+                    if let Some(entry) = device_menu.find_mut(&device_id) {
+                        entry.set_last_seen(Some(now));
+                    }
+
+                    // A device flapping at the edge of range shows up over and over
+                    let device_id_str = device_id.to_string();
+                    let cooled_down = auto_connect_attempts
+                        .get(&device_id_str)
+                        .is_none_or(|at| at.elapsed() >= config.auto_connect_cooldown());
+                    if radio_on && cooled_down && contains_device_id(&config.auto_connect_devices, &device_id_str) {
+                        let mut manager = connection_manager_clone.lock().unwrap();
+                        let paused = config.pause_reconnect_when_flaky && manager.is_flaky(&device_id_str);
+                        if let Some(entry) = device_menu.find_mut(&device_id).filter(|_| !paused) {
+                            if start_connect(&proxy, &mut manager, entry, config.retry) {
+                                info!(%device_id, "Came into range, connecting");
+                                auto_connect_attempts.insert(device_id_str, Instant::now());
+                            }
+                        }
+                    }
+                }
+            },
+
+            Event::UserEvent(UserEvent::RadioToggleFailed(e)) => {
+                error!(error = %e, "Failed to switch Bluetooth radio");
+                if matches!(e, BlueTrayError::RadioAccessDenied) {
+                    notifier.alert("Could not switch Bluetooth", &e.to_string());
+                } else {
+                    notifier.error("Could not switch Bluetooth", &e.to_string());
+                }
+            }
+
+            Event::UserEvent(UserEvent::ConnectRetrying {
+                device_id,
+                attempt,
+                max_attempts,
+            }) => {
+                if let Some(entry) = device_menu.find_mut(&device_id) {
+                    entry.set_retrying(attempt, max_attempts);
+                }
+            }
+
+            Event::UserEvent(UserEvent::DeviceTested { device_id, name, test }) => {
+                let summary = test.summary();
+                let title = format!("Connection test: {}", name);
+                if test.passed() {
+                    info!(%device_id, %summary, "Connection test passed");
+                    notifier.info(&title, &summary);
+                } else {
+                    warn!(%device_id, %summary, "Connection test failed");
+                    notifier.error(&title, &summary);
+                }
+            }
+
+            Event::UserEvent(UserEvent::ConnectionsLost(device_ids)) => {
+                let mut manager = connection_manager_clone.lock().unwrap();
+                for device_id in &device_ids {
+                    let device_id_str = device_id.to_string();
+                    // Reports can trail a disconnect we did ourselves
+                    if !manager.is_connected(&device_id_str) {
+                        continue;
+                    }
+                    let Some(entry) = device_menu.find_mut(device_id) else {
+                        manager.disconnect_device(&device_id_str);
+                        continue;
+                    };
+                    warn!(%device_id, "Connection lost");
+                    history.record(&device_id_str, &entry.name, HistoryAction::Lost, None);
+                    entry.show_connected(false);
+                    entry.clear_status();
+
+                    let paused = config.pause_reconnect_when_flaky && manager.is_flaky(&device_id_str);
+                    if contains_device_id(&config.reconnect_when_lost, &device_id_str)
+                        && !paused
+                        && manager.reconnect(device_id, entry.name.clone())
+                    {
+                        notifier.error("Connection lost", &format!("Lost connection to {}, reconnecting", entry.name));
+                        entry.set_connecting(true);
+                        spawn_connect(
+                            &proxy,
+                            &mut manager,
+                            device_id.clone(),
+                            entry.kind,
+                            entry.preferred_service,
+                            config.retry,
+                        );
+                    } else {
+                        manager.disconnect_device(&device_id_str);
+                        notifier.error("Connection lost", &format!("Lost connection to {}", entry.name));
+                        entry.set_lost(true);
+                    }
+                }
+                saved_state.set_connected_devices(manager.connected_ids());
+                fill_history_menu(&history_menu, &history);
+            }
+
+            Event::UserEvent(UserEvent::ConnectionResult { device_id, result }) => {
+                let mut manager = connection_manager_clone.lock().unwrap();
+                let name = device_menu.name(&device_id);
+                let connect_time = result.as_ref().ok().map(|(_, elapsed)| *elapsed);
+                let outcome = manager.connect_device(&device_id, result.map(|(link, _)| link));
+                let response = match &outcome {
+                    Ok(()) => ipc::Response::ok(format!("Connected to {}", name)),
+                    Err(e) => ipc::Response::error(format!("Could not connect to {}: {}", name, e)),
+                };
+                for reply in pending_connects.remove(&device_id.to_string()).unwrap_or_default() {
+                    let _ = reply.send(response.clone());
+                }
+                let summary = reconnect_batch
+                    .as_mut()
+                    .and_then(|batch| batch.record(&device_id.to_string(), &name, outcome.is_ok()));
+                if let Some(summary) = summary {
+                    notifier.info("Reconnect all", &summary);
+                    reconnect_batch = None;
+                }
+                match outcome {
+                    Ok(()) => {
+                        info!(%device_id, %name, ?connect_time, "Connected");
+                        if let Some(elapsed) = connect_time {
+                            manager.record_connect_time(&device_id.to_string(), elapsed);
+                        }
+                        if let Some(entry) = device_menu.find_mut(&device_id) {
+                            entry.set_lost(false);
+                            entry.set_last_seen(Some(SystemTime::now()));
+                            entry.set_connected_for(Some(Duration::ZERO));
+                        }
+                        saved_state.set_last_seen(&device_id.to_string(), SystemTime::now());
+                        history.record(&device_id.to_string(), &name, HistoryAction::Connect, None);
+                        saved_state.last_device = Some(device_id.to_string());
+                        saved_state.set_connected_devices(manager.connected_ids());
+
+                        let is_audio = device_menu.find(&device_id).is_some_and(|entry| entry.class == DeviceClass::Audio);
+                        let switch_to = contains_device_id(&config.default_audio_devices, &device_id.to_string());
+                        if is_audio && switch_to && config.switch_audio_default {
+                            spawn_make_default_output(&proxy, device_id.clone());
+                        }
+
+                        // Serial-style links get a reader so the app sees what the device sends
+                        let device_id_str = device_id.to_string();
+                        if let Some(DeviceConnection::Rfcomm(socket)) = manager.link(&device_id_str) {
+                            if !manager.has_reader(&device_id_str) {
+                                let reader = spawn_reader(&proxy, device_id.clone(), socket.clone());
+                                manager.set_reader(&device_id_str, reader);
+                            }
+                        }
+                    }
+                    Err(BlueTrayError::Cancelled) => info!(%device_id, %name, "Connect called off"),
+                    Err(e) => {
+                        error!(%device_id, %name, error = %e, "Failed to connect to device");
+                        history.record(&device_id.to_string(), &name, HistoryAction::Connect, Some(e.to_string()));
+                        // A device that's off or out of range is routine; anything else needs a look
+                        if e.is_transient() {
+                            notifier.error(&format!("Could not connect to {}", name), &e.to_string());
+                        } else {
+                            notifier.alert(&format!("Could not connect to {}", name), &e.to_string());
+                        }
+                        // Unpaired since the last enumeration; drop it now and let a refresh
+                        // bring the list up to date
+                        if matches!(e, BlueTrayError::DeviceNotFound(_)) {
+                            device_menu.remove_device(&device_id, &mut manager);
+                            spawn_refresh(&proxy, platform);
+                        }
+                    }
+                }
+
+                fill_history_menu(&history_menu, &history);
+            }
+
+            Event::UserEvent(UserEvent::ConnectionStateChanged(change)) => {
+                let device_id = HSTRING::from(&change.device_id);
+                let manager = connection_manager_clone.lock().unwrap();
+                // The device may have moved on again by the time this arrives, so the item
+                // shows where it is now
+                let state = manager.state(&change.device_id);
+                if let Some(entry) = device_menu.find_mut(&device_id) {
+                    entry.set_connecting(state == ConnectionState::Connecting);
+                    entry.set_flaky(manager.is_flaky(&change.device_id));
+                    entry.show_connected(state == ConnectionState::Connected || entry.os_connected);
+                }
+                if change.state == ConnectionState::Connected {
+                    notifier.info("Connected", &format!("Connected to {}", device_menu.name(&device_id)));
+                }
+            }
+
+            Event::UserEvent(UserEvent::ForgetConfirmed(device_id)) => {
+                // Our own link goes first so nothing is left pointing at the unpaired device
+                let mut manager = connection_manager_clone.lock().unwrap();
+                let device_id_str = device_id.to_string();
+                if manager.disconnect_device(&device_id_str) {
+                    history.record(&device_id_str, &device_menu.name(&device_id), HistoryAction::Disconnect, None);
+                    fill_history_menu(&history_menu, &history);
+                    saved_state.set_connected_devices(manager.connected_ids());
+                }
+                if let Some(entry) = device_menu.find_mut(&device_id) {
+                    entry.clear_status();
+                    entry.show_connected(false);
+                }
+                let proxy = proxy.clone();
+                tasks::spawn(async move {
+                    let result = pairing::unpair_device(&device_id).await;
+                    let _ = proxy.send_event(UserEvent::Unpaired { device_id, result });
+                });
+            }
+
+            // On failure the item stays, disconnected, so it can be tried again
+            Event::UserEvent(UserEvent::Unpaired { device_id, result }) => {
+                let name = device_menu.name(&device_id);
+                match result {
+                    Ok(()) => {
+                        device_menu.remove_device(&device_id, &mut connection_manager_clone.lock().unwrap());
+                        notifier.info("Device removed", &format!("Forgot {}", name));
+                    }
+                    Err(e) => {
+                        warn!(%device_id, error = %e, "Failed to unpair");
+                        notifier.error(&format!("Could not forget {}", name), &e.to_string());
+                    }
+                }
+            }
+
+            Event::UserEvent(UserEvent::UnpairedDevicesFound(devices)) => pair_menu.show_results(devices),
+
+            // The paired device watcher adds it to the device list
+            Event::UserEvent(UserEvent::PairingFinished { device, result }) => {
+                pair_menu.finish_pairing(&device.device_id, result.is_ok());
+                match result {
+                    Ok(()) => notifier.info("Paired", &format!("Paired with {}", device.name)),
+                    Err(e) => {
+                        warn!(device_id = %device.device_id, error = %e, "Failed to pair");
+                        notifier.error(&format!("Could not pair with {}", device.name), &e.to_string());
+                    }
+                }
+            }
+
+            // Restored below once the device is no longer connected
+            Event::UserEvent(UserEvent::AudioSwitched { device_id, endpoint, previous }) if config.restore_audio_default => {
+                audio_switcher.switched(device_id.to_string(), endpoint, previous);
+            }
+
+            Event::UserEvent(UserEvent::SignalStrengths(signals)) => {
+                let manager = connection_manager_clone.lock().unwrap();
+                for (device_id, rssi) in signals {
+                    let rssi = rssi.filter(|_| manager.is_connected(&device_id.to_string()));
+                    if let Some(entry) = device_menu.find_mut(&device_id) {
+                        entry.set_rssi(rssi);
+                    }
+                }
+            }
+
+            Event::UserEvent(UserEvent::DataReceived { device_id, bytes }) => {
+                debug!(%device_id, len = bytes.len(), data = %String::from_utf8_lossy(&bytes), "Data received");
+                let mut manager = connection_manager_clone.lock().unwrap();
+                manager.record_received(&device_id.to_string(), bytes.len());
+            }
+
+            Event::UserEvent(UserEvent::BatteryLevels(levels)) => {
+                let manager = connection_manager_clone.lock().unwrap();
+                for (device_id, level) in levels {
+                    // A reading may arrive after the device was disconnected
+                    let level = level.filter(|_| manager.is_connected(&device_id.to_string()));
+                    if let Some(entry) = device_menu.find_mut(&device_id) {
+                        entry.set_battery(level);
                     }
                 }
             }
 
             _ => {}
         }
+
+        let radio_label = radio_toggle_label(radio_on);
+        if radio_i.text() != radio_label {
+            radio_i.set_text(radio_label);
+        }
+
+        // Put the previous default output back for audio devices that went away, however
+        // they were disconnected
+        for device_id in audio_switcher.device_ids() {
+            if !connection_manager_clone.lock().unwrap().is_connected(&device_id) {
+                audio_switcher.restore(&device_id);
+            }
+        }
+
+        // Both follow the connected marks, whichever event changed them
+        device_menu.reorder_if_needed();
+        device_menu.update_summary();
+
+        // Names come from the menu, so wait for the devices rather than clearing the list
+        // while they load
+        if !device_menu.devices.is_empty() {
+            let jump_devices = jump_list_devices(&config, &device_menu);
+            if jump_list_shown.as_ref() != Some(&jump_devices) {
+                spawn_set_jump_list(jump_devices.clone());
+                jump_list_shown = Some(jump_devices);
+            }
+        }
+
+        // Keep the icon and tooltip in step with what is connected
+        if let Some(tray_icon) = &tray_icon {
+            let manager = connection_manager_clone.lock().unwrap();
+            let animating = radio_on && manager.has_pending_connects();
+            if !animating {
+                connecting_since = None;
+            }
+            let state = if !radio_on {
+                TrayState::RadioOff
+            } else if animating {
+                let started = *connecting_since.get_or_insert_with(Instant::now);
+                let frame = started.elapsed().as_millis() / CONNECTING_FRAME_INTERVAL.as_millis();
+                // Wake up for the next frame, unless the loop is on its way out
+                if *control_flow == ControlFlow::Wait {
+                    *control_flow = ControlFlow::WaitUntil(started + CONNECTING_FRAME_INTERVAL * (frame as u32 + 1));
+                }
+                TrayState::Connecting(frame as usize % CONNECTING_FRAMES)
+            } else if manager.has_connections() {
+                TrayState::Connected
+            } else {
+                TrayState::Disconnected
+            };
+            if state != showing_state {
+                if let Err(e) = tray_icon.set_icon(icons.for_state(state)) {
+                    warn!(error = %e, "Failed to update tray icon");
+                }
+                showing_state = state;
+            }
+
+            let mut names: Vec<String> = manager
+                .list_connections()
+                .into_iter()
+                .filter(|connection| connection.state == ConnectionState::Connected)
+                .map(|connection| {
+                    let traffic = connection.bytes_sent + connection.bytes_received;
+                    if config.tooltip_throughput && traffic > 0 {
+                        format!(
+                            "{} (↑{} ↓{})",
+                            connection.name,
+                            format_bytes(connection.bytes_sent),
+                            format_bytes(connection.bytes_received)
+                        )
+                    } else {
+                        connection.name
+                    }
+                })
+                .collect();
+            names.sort();
+            let tooltip = connection_tooltip(&names);
+            if tooltip != showing_tooltip {
+                if let Err(e) = tray_icon.set_tooltip(Some(&tooltip)) {
+                    warn!(error = %e, "Failed to update tray tooltip");
+                }
+                showing_tooltip = tooltip;
+            }
+        }
     })
 }
 
-async fn get_paired_bluetooth_devices() -> Result<Vec<DeviceInformation>, Error> {
-    let selector = BluetoothDevice::GetDeviceSelectorFromPairingState(true)?;
-    let devices_operation = DeviceInformation::FindAllAsyncAqsFilter(&selector)?;
-    let devices: Vec<_> = devices_operation.get()?.into_iter().collect();
+// e.g. "512 B", "1.5 KB", "12.0 MB"
+fn format_bytes(bytes: u64) -> String {
+    match bytes {
+        0..1024 => format!("{} B", bytes),
+        1024..1_048_576 => format!("{:.1} KB", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MB", bytes as f64 / 1_048_576.0),
+    }
+}
+
+// Windows cuts tray tooltips off at 128 characters, so stay well under that
+const MAX_TOOLTIP_LEN: usize = 100;
+
+fn connection_tooltip(names: &[String]) -> String {
+    if names.is_empty() {
+        return "Bluetray - No devices connected".to_string();
+    }
+
+    let mut tooltip = "Connected: ".to_string();
+    for (shown, name) in names.iter().enumerate() {
+        let separator = if shown == 0 { "" } else { ", " };
+        let remaining = names.len() - shown;
+        let suffix = format!(" and {} more", remaining);
+        if tooltip.chars().count() + separator.len() + name.chars().count() + suffix.len() > MAX_TOOLTIP_LEN {
+            if shown == 0 {
+                // A single absurdly long name still gets shown, just cut short
+                let room = MAX_TOOLTIP_LEN - tooltip.chars().count() - suffix.len() - 1;
+                tooltip.extend(name.chars().take(room));
+                tooltip.push('…');
+                if remaining > 1 {
+                    tooltip.push_str(&format!(" and {} more", remaining - 1));
+                }
+            } else {
+                tooltip.push_str(&suffix);
+            }
+            break;
+        }
+        tooltip.push_str(separator);
+        tooltip.push_str(name);
+    }
+
+    tooltip
+}
+
+fn register_hotkey(combo: &str, proxy: EventLoopProxy<UserEvent>) -> Option<GlobalHotKeyManager> {
+    if combo.is_empty() {
+        return None;
+    }
+    let hotkey: HotKey = match combo.parse() {
+        Ok(hotkey) => hotkey,
+        Err(e) => {
+            warn!(combo, error = %e, "Ignoring invalid hotkey");
+            return None;
+        }
+    };
+    let manager = GlobalHotKeyManager::new()
+        .and_then(|manager| manager.register(hotkey).map(|()| manager))
+        .inspect_err(|e| warn!(combo, error = %e, "Failed to register hotkey"))
+        .ok()?;
+
+    GlobalHotKeyEvent::set_event_handler(Some(move |event: GlobalHotKeyEvent| {
+        if event.state() == HotKeyState::Pressed {
+            let _ = proxy.send_event(UserEvent::Hotkey);
+        }
+    }));
+    Some(manager)
+}
+
+fn radio_toggle_label(radio_on: bool) -> &'static str {
+    if radio_on {
+        "Turn Bluetooth off"
+    } else {
+        "Turn Bluetooth on"
+    }
+}
 
-    Ok(devices)
+// The favorite device, then the pinned ones, leaving out any that aren't paired
+fn jump_list_devices(config: &Config, device_menu: &DeviceMenu) -> Vec<JumpDevice> {
+    let mut devices: Vec<JumpDevice> = Vec::new();
+    for device_id in config.favorite_device.iter().chain(&config.pinned_devices) {
+        let Some(entry) = device_menu.find(&HSTRING::from(device_id)) else {
+            continue;
+        };
+        let device_id = entry.device_id.to_string();
+        if !devices.iter().any(|device| device.device_id == device_id) {
+            devices.push(JumpDevice {
+                device_id,
+                name: entry.name.clone(),
+            });
+        }
+    }
+    devices
 }
 
-fn connect_to_bluetooth_device(device_id: &HSTRING) -> Result<StreamSocket, Error> {
-    println!("Attempting to connect to device with ID: {:?}", device_id);
-    let device = BluetoothDevice::FromIdAsync(device_id)?.get()?;
-    let service = device.GetRfcommServicesAsync()?.get()?.Services()?.GetAt(0)?;
-    let socket = StreamSocket::new()?;
-    println!("Connecting to device: {:?}, {:?}", service.ConnectionHostName()?.ToString()?, service.ConnectionServiceName()?);
-    let _connection = socket.ConnectAsync(
-        &service.ConnectionHostName()?, 
-        &service.ConnectionServiceName()?)?.get()?;
-    println!("Connected to device: {:?}", device.Name()?);
-    
-    Ok(socket)
+fn spawn_set_jump_list(devices: Vec<JumpDevice>) {
+    tasks::spawn_blocking(move || {
+        if let Err(e) = jumplist::set_devices(&devices) {
+            warn!(error = %e, "Failed to update the taskbar jump list");
+        }
+    });
+}
+
+fn spawn_refresh(proxy: &EventLoopProxy<UserEvent>, platform: WindowsPlatform) {
+    let proxy = proxy.clone();
+    tasks::spawn(async move {
+        let devices = platform.list_paired_devices().await;
+        let _ = proxy.send_event(UserEvent::DevicesLoaded(devices));
+    });
+}
+
+// Starts connecting unless the device is already connected or an attempt is in flight
+fn start_connect(
+    proxy: &EventLoopProxy<UserEvent>,
+    manager: &mut ConnectionManager,
+    entry: &mut DeviceEntry,
+    retry: RetryPolicy,
+) -> bool {
+    let Some(UiAction::Connect {
+        device_id,
+        kind,
+        preferred_service,
+        retry,
+    }) = app::start_connect(manager, entry, retry)
+    else {
+        return false;
+    };
+    spawn_connect(proxy, manager, device_id, kind, preferred_service, retry);
+    true
+}
+
+// Runs right before the event loop exits, which ends the process without unwinding the
+// runtime. Closes every link, gives the background tasks still working a moment to finish so
+// their last log lines are written, aborts the rest, then saves the state and flushes the logs.
+fn shutdown(manager: &Arc<Mutex<ConnectionManager>>, saved_state: &SavedState) {
+    info!("Shutting down");
+    // Closed explicitly so devices that allow only one connection are free right away.
+    // Each close that fails is logged with its device.
+    manager.lock().unwrap().disconnect_all();
+
+    // Waited for from a worker, since this thread is the one the event loop runs on
+    let (done_tx, done) = std::sync::mpsc::channel();
+    tokio::spawn(async move {
+        let _ = done_tx.send(tasks::shutdown(SHUTDOWN_DEADLINE).await);
+    });
+    match done.recv_timeout(SHUTDOWN_DEADLINE + Duration::from_secs(1)) {
+        Ok(0) => {}
+        Ok(aborted) => info!(aborted, "Aborted background tasks at exit"),
+        Err(_) => warn!(deadline = ?SHUTDOWN_DEADLINE, "Background tasks did not stop, leaving them"),
+    }
+    // Last-seen times are only saved every so often while running
+    saved_state.save();
+    info!("Shutdown finished");
+    logging::flush();
 }
+
+// Carries out what the menu handling asked for. Returns whether the app should quit.
+fn apply_actions(
+    actions: Vec<UiAction>,
+    proxy: &EventLoopProxy<UserEvent>,
+    manager: &mut ConnectionManager,
+    notifier: &Notifier,
+    saved_state: &mut SavedState,
+    history: &mut History,
+    history_menu: &Submenu,
+) -> bool {
+    let mut quit = false;
+    let mut history_changed = false;
+    for action in actions {
+        match action {
+            UiAction::Quit => quit = true,
+            UiAction::Connect {
+                device_id,
+                kind,
+                preferred_service,
+                retry,
+            } => spawn_connect(proxy, manager, device_id, kind, preferred_service, retry),
+            UiAction::Notify { title, message } => notifier.info(&title, &message),
+            UiAction::Record { device_id, name, action } => {
+                history.record(&device_id, &name, action, None);
+                history_changed = true;
+            }
+            UiAction::SaveConnected => saved_state.set_connected_devices(manager.connected_ids()),
+            UiAction::Refresh => spawn_refresh(proxy, *manager.platform()),
+        }
+    }
+    if history_changed {
+        fill_history_menu(history_menu, history);
+    }
+    quit
+}
+
+// Connecting can take seconds, so do it off the event loop and report back through the proxy.
+// Waits for one of the manager's connect slots first, so a burst of reconnects queues up.
+fn spawn_connect(
+    proxy: &EventLoopProxy<UserEvent>,
+    manager: &mut ConnectionManager,
+    device_id: HSTRING,
+    kind: DeviceKind,
+    preferred_service: Option<GUID>,
+    retry: RetryPolicy,
+) {
+    let proxy = proxy.clone();
+    let platform = *manager.platform();
+    let slots = manager.connect_slots();
+    let stop = manager.retry_stop(&device_id.to_string());
+    let span = info_span!("connect", %device_id, ?kind);
+    let device_id_str = device_id.to_string();
+    let task = tasks::spawn(
+        async move {
+            // Held until this attempt is over, however it ends
+            let _slot = slots.acquire_owned().await;
+            let on_retry = |attempt, max_attempts| {
+                let _ = proxy.send_event(UserEvent::ConnectRetrying {
+                    device_id: device_id.clone(),
+                    attempt,
+                    max_attempts,
+                });
+            };
+            let result =
+                connect_with_retry(&platform, &device_id, kind, preferred_service, retry, &stop, on_retry).await;
+            let connected = result.is_ok();
+            let _ = proxy.send_event(UserEvent::ConnectionResult { device_id: device_id.clone(), result });
+
+            // Show the battery right away instead of waiting for the next health check
+            if connected {
+                let level = platform.battery(&device_id).await;
+                let _ = proxy.send_event(UserEvent::BatteryLevels(vec![(device_id, level)]));
+            }
+        }
+        .instrument(span),
+    );
+    manager.set_connect_task(&device_id_str, task);
+}
+
+// Switching the default output goes through COM and can take a moment
+fn spawn_make_default_output(proxy: &EventLoopProxy<UserEvent>, device_id: HSTRING) {
+    let proxy = proxy.clone();
+    tasks::spawn(async move {
+        match audio::make_default_output(&device_id).await {
+            Ok(Some((endpoint, previous))) => {
+                let _ = proxy.send_event(UserEvent::AudioSwitched { device_id, endpoint, previous });
+            }
+            Ok(None) => debug!(%device_id, "No audio output to switch to"),
+            Err(e) => warn!(%device_id, error = %e, "Failed to make the device the default audio output"),
+        }
+    });
+}
+
+// Largest chunk a single read asks for; partial reads return whatever has arrived
+const READ_CHUNK: u32 = 1024;
+
+// Forwards whatever the device sends to the event loop until the link closes. A closed or
+// failed stream means the device went away, which is reported as a lost connection. The
+// manager aborts the task when the device is disconnected from our side.
+fn spawn_reader(
+    proxy: &EventLoopProxy<UserEvent>,
+    device_id: HSTRING,
+    socket: StreamSocket,
+) -> tokio::task::AbortHandle {
+    let proxy = proxy.clone();
+    let span = info_span!("reader", %device_id);
+    tasks::spawn_loop(
+        async move {
+            let result: windows::core::Result<()> = async {
+                let reader = DataReader::CreateDataReader(&socket.InputStream()?)?;
+                reader.SetInputStreamOptions(InputStreamOptions::Partial)?;
+                loop {
+                    let loaded = reader.LoadAsync(READ_CHUNK)?.await?;
+                    if loaded == 0 {
+                        return Ok(());
+                    }
+                    let mut bytes = vec![0; loaded as usize];
+                    reader.ReadBytes(&mut bytes)?;
+                    if proxy
+                        .send_event(UserEvent::DataReceived { device_id: device_id.clone(), bytes })
+                        .is_err()
+                    {
+                        return Ok(());
+                    }
+                }
+            }
+            .await;
+
+            match result {
+                Ok(()) => debug!("Device closed the stream"),
+                Err(e) => debug!(error = %e, "Read failed"),
+            }
+            let _ = proxy.send_event(UserEvent::ConnectionsLost(vec![device_id]));
+        }
+        .instrument(span),
+    )
+}
+
+// The Bluetooth stack needs a moment after wake before devices answer
+const RESUME_SETTLE_DELAY: Duration = Duration::from_secs(5);
+
+// Reports every link that didn't survive the sleep as lost, then posts Resumed so the loop
+// can bring the devices back
+async fn check_after_resume(manager: Arc<Mutex<ConnectionManager>>, proxy: EventLoopProxy<UserEvent>) {
+    tokio::time::sleep(RESUME_SETTLE_DELAY).await;
+    let (platform, connected) = {
+        let manager = manager.lock().unwrap();
+        (*manager.platform(), manager.connected_ids())
+    };
+    let mut lost = Vec::new();
+    for device_id in &connected {
+        let device_id = HSTRING::from(device_id);
+        if !platform.is_connected(&device_id).await {
+            lost.push(device_id);
+        }
+    }
+    info!(connected = connected.len(), lost = lost.len(), "Checked connections after sleep");
+    if !lost.is_empty() {
+        let _ = proxy.send_event(UserEvent::ConnectionsLost(lost));
+    }
+    let _ = proxy.send_event(UserEvent::Resumed(connected));
+}
+
+// Periodically looks for stored connections whose device dropped off and reports them to
+// the event loop, which owns the menu and does the actual cleanup. Battery levels and
+// signal strengths of the devices still connected are refreshed on the same tick, and
+// devices with a keepalive get it written.
+fn spawn_health_check(
+    manager: Arc<Mutex<ConnectionManager>>,
+    proxy: EventLoopProxy<UserEvent>,
+    mut interval: tokio::sync::watch::Receiver<Duration>,
+    keepalive: HashMap<String, String>,
+) {
+    let platform = *manager.lock().unwrap().platform();
+    tasks::spawn_loop(async move {
+        let mut ticker = tokio::time::interval(*interval.borrow_and_update());
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => {}
+                changed = interval.changed() => {
+                    // The sender only goes away with the event loop
+                    if changed.is_err() {
+                        break;
+                    }
+                    let period = *interval.borrow_and_update();
+                    info!(?period, "Health check interval changed");
+                    ticker = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+                    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+                    continue;
+                }
+            }
+
+            // Don't hold the lock across the WinRT calls below
+            let connections = manager.lock().unwrap().list_connections();
+            let mut lost = Vec::new();
+            let mut levels = Vec::new();
+            let mut signals = Vec::new();
+            for connection in connections {
+                if connection.state != ConnectionState::Connected {
+                    continue;
+                }
+                if let Some(payload) = get_by_device_id(&keepalive, &connection.device_id) {
+                    // A failed write is left to the reader to notice if the link is really gone
+                    let write = manager.lock().unwrap().send(&connection.device_id, payload.as_bytes());
+                    match write.await {
+                        Ok(()) => manager.lock().unwrap().record_sent(&connection.device_id, payload.len()),
+                        Err(e) => debug!(device_id = %connection.device_id, error = %e, "Keepalive write failed"),
+                    }
+                }
+                let device_id = HSTRING::from(connection.device_id);
+                if platform.is_connected(&device_id).await {
+                    let level = platform.battery(&device_id).await;
+                    let rssi = platform.signal_strength(&device_id).await;
+                    levels.push((device_id.clone(), level));
+                    signals.push((device_id, rssi));
+                } else {
+                    info!(%device_id, name = %connection.name, "Health check: device is no longer connected");
+                    lost.push(device_id);
+                }
+            }
+
+            if !lost.is_empty() && proxy.send_event(UserEvent::ConnectionsLost(lost)).is_err() {
+                // The event loop is gone, nothing left to check for
+                break;
+            }
+            if !levels.is_empty() && proxy.send_event(UserEvent::BatteryLevels(levels)).is_err() {
+                break;
+            }
+            if !signals.is_empty() && proxy.send_event(UserEvent::SignalStrengths(signals)).is_err() {
+                break;
+            }
+        }
+    });
+}
+
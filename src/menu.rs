@@ -0,0 +1,1274 @@
+use std::collections::HashMap;
+use std::panic::Location;
+use std::time::{Duration, Instant, SystemTime};
+
+use tracing::{info, warn};
+use tray_icon::menu::{
+    CheckMenuItem, Error as MenuError, Icon, IconMenuItem, IsMenuItem, Menu, MenuId, MenuItem, PredefinedMenuItem,
+    Submenu,
+};
+use windows::core::{GUID, HSTRING};
+
+use crate::bluetooth::{format_address, AdapterInfo, DeviceClass, DeviceKind, PairedDevice, RfcommProfile};
+use crate::connection::{contains_device_id, get_by_device_id, same_device_id, ConnectionManager};
+use crate::error::BlueTrayError;
+use crate::history::History;
+use crate::icons::ClassIcons;
+use crate::ipc::ListingFormat;
+use crate::pairing::UnpairedDevice;
+use crate::platform::BluetoothPlatform;
+use crate::state::SavedState;
+use crate::config::DeviceOrder;
+
+// Key in connect_profiles for the service to use on devices without their own
+const ALL_DEVICES_PROFILE: &str = "*";
+
+// Repeat clicks on a device within this window are taken as accidental
+const CLICK_DEBOUNCE: Duration = Duration::from_millis(750);
+
+// Adding to a menu only fails when the native menu does, which is no reason to take the
+// tray down with it. The failure is logged and the item left out.
+pub trait OrLog {
+    fn or_log(self);
+}
+
+impl OrLog for Result<(), MenuError> {
+    #[track_caller]
+    fn or_log(self) {
+        if let Err(e) = self {
+            warn!(error = %e, location = %Location::caller(), "Failed to add a menu item, leaving it out");
+        }
+    }
+}
+
+// Position of the first device item, right after the summary, the About item and its
+// separator
+const DEVICE_SECTION_START: usize = 3;
+
+// A device's menu item along with everything shown in its label
+pub struct DeviceEntry {
+    pub device_id: HSTRING,
+    pub kind: DeviceKind,
+    pub class: DeviceClass,
+    pub name: String,
+    // Shows the class icon, badged while connected
+    pub item: IconMenuItem,
+    icon: Option<Icon>,
+    connected_icon: Option<Icon>,
+    shows_connected: bool,
+    // Whether Windows had the device connected at the last sync, e.g. through a profile
+    // this app didn't open, which marks it connected whatever the manager says
+    pub os_connected: bool,
+    pub address: Option<u64>,
+    // Entries in the "Copy device ID" submenu
+    copy_id_item: MenuItem,
+    copy_address_item: Option<MenuItem>,
+    // Entry in the "Pin devices" submenu
+    pin_item: MenuItem,
+    pinned: bool,
+    // Entry in the "Auto-connect when in range" submenu
+    auto_connect_item: CheckMenuItem,
+    // Entry in the "Test connection" submenu
+    test_item: MenuItem,
+    // Entry in the "Forget device" submenu
+    forget_item: MenuItem,
+    // RFCOMM services to choose from, with a submenu under "Connection profile" once there
+    // is more than one. None in the items stands for the first service.
+    profiles: Vec<RfcommProfile>,
+    profile_menu: Option<Submenu>,
+    profile_items: Vec<(Option<GUID>, CheckMenuItem)>,
+    // Service connects go to, from the config; None uses the first one
+    pub preferred_service: Option<GUID>,
+    pub battery: Option<u8>,
+    // Signal strength in dBm, for devices that report it
+    pub rssi: Option<i16>,
+    // How long the device has been connected, to the minute
+    connected_minutes: Option<u64>,
+    // When the device was last in range, and how many minutes ago that was as last shown
+    last_seen: Option<SystemTime>,
+    seen_minutes_ago: Option<u64>,
+    connecting: bool,
+    // Attempt being made and the most there will be, once the first one has failed
+    retrying: Option<(u32, u32)>,
+    // Entry in the "Stop retrying" submenu, enabled only while retrying
+    stop_retry_item: MenuItem,
+    // Entry in the "Cancel connecting" submenu, enabled only while connecting
+    cancel_item: MenuItem,
+    // The connection dropped on its own; cleared once the device connects again
+    lost: bool,
+    // Failed to connect too many times in a row, see ConnectionManager::is_flaky
+    flaky: bool,
+    last_click: Option<Instant>,
+    // Whether the device has anything to connect to, as of the last enumeration
+    pub connectable: bool,
+    // Position in the last enumeration, for the raw ordering
+    position: usize,
+}
+
+impl DeviceEntry {
+    // Same device, however the id was cased or padded by the API it came from
+    fn is_device(&self, device_id: &HSTRING) -> bool {
+        self.device_id == *device_id || same_device_id(&self.device_id.to_string(), &device_id.to_string())
+    }
+
+    fn label(&self) -> String {
+        let name = if self.flaky {
+            format!("⚠ {}", self.name)
+        } else if self.shows_connected && self.connected_icon.is_none() {
+            // Nothing else would mark it connected without an icon
+            format!("✓ {}", self.name)
+        } else {
+            self.name.clone()
+        };
+        if let (true, Some((attempt, max_attempts))) = (self.connecting, self.retrying) {
+            return format!("{} - Retrying ({}/{})…", name, attempt, max_attempts);
+        }
+        if self.connecting {
+            return format!("{} - Connecting…", name);
+        }
+        // Disabled items say why, rather than just looking broken
+        if !self.connectable {
+            return format!("{} (not connectable)", name);
+        }
+        if self.lost {
+            return format!("{} (connection lost, click to reconnect)", name);
+        }
+        let name = match (self.connected_minutes, self.seen_minutes_ago) {
+            (Some(minutes), _) => format!("{} — {}", name, format_minutes(minutes)),
+            // Connected devices show their uptime instead
+            (None, Some(ago)) => format!("{} — last seen {}", name, format_age(ago)),
+            _ => name,
+        };
+        let mut status = Vec::new();
+        if let Some(level) = self.battery {
+            status.push(format!("{}%", level));
+        }
+        if let Some(rssi) = self.rssi {
+            status.push(format!("{} dBm", rssi));
+        }
+        if status.is_empty() {
+            name
+        } else {
+            format!("{} ({})", name, status.join(", "))
+        }
+    }
+
+    // The event loop goes by the manager; this is for checking the item in tests
+    #[cfg(test)]
+    pub fn shows_connected(&self) -> bool {
+        self.shows_connected
+    }
+
+    pub fn show_connected(&mut self, connected: bool) {
+        if self.shows_connected != connected {
+            self.shows_connected = connected;
+            self.update_icon();
+        }
+    }
+
+    fn set_class(&mut self, class: DeviceClass, icons: &ClassIcons) {
+        if self.class != class || self.icon.is_none() {
+            self.class = class;
+            self.icon = icons.for_class(class, false);
+            self.connected_icon = icons.for_class(class, true);
+            self.update_icon();
+        }
+    }
+
+    fn update_icon(&self) {
+        let icon = if self.shows_connected { &self.connected_icon } else { &self.icon };
+        self.item.set_icon(icon.clone());
+        self.item.set_text(self.label());
+    }
+
+    fn set_name(&mut self, name: String) {
+        if self.name != name {
+            self.name = name;
+            self.item.set_text(self.label());
+            self.copy_id_item.set_text(&self.name);
+            if let Some(item) = &self.copy_address_item {
+                item.set_text(copy_address_label(&self.name));
+            }
+            self.pin_item.set_text(self.pin_label());
+            self.auto_connect_item.set_text(&self.name);
+            self.test_item.set_text(&self.name);
+            self.stop_retry_item.set_text(&self.name);
+            self.cancel_item.set_text(&self.name);
+            self.forget_item.set_text(&self.name);
+            if let Some(menu) = &self.profile_menu {
+                menu.set_text(&self.name);
+            }
+        }
+    }
+
+    // Rebuilds the profile submenu when the device's services changed
+    fn set_profiles(&mut self, parent: &Submenu, profiles: Vec<RfcommProfile>) {
+        if self.profiles == profiles {
+            return;
+        }
+        if let Some(menu) = self.profile_menu.take() {
+            let _ = parent.remove(&menu);
+        }
+        self.profile_items.clear();
+        self.profiles = profiles;
+        if self.profiles.len() < 2 {
+            return;
+        }
+
+        let menu = Submenu::new(&self.name, true);
+        let choices = std::iter::once((None, "Automatic".to_string()))
+            .chain(self.profiles.iter().map(|profile| (Some(profile.uuid), profile.name.clone())));
+        for (uuid, label) in choices {
+            let item = CheckMenuItem::new(label, true, uuid == self.preferred_service, None);
+            menu.append(&item).or_log();
+            self.profile_items.push((uuid, item));
+        }
+        parent.append(&menu).or_log();
+        self.profile_menu = Some(menu);
+    }
+
+    fn set_preferred_service(&mut self, preferred_service: Option<GUID>) {
+        self.preferred_service = preferred_service;
+        for (uuid, item) in &self.profile_items {
+            item.set_checked(*uuid == preferred_service);
+        }
+    }
+
+    fn pin_label(&self) -> String {
+        if self.pinned {
+            format!("Unpin {}", self.name)
+        } else {
+            format!("Pin {}", self.name)
+        }
+    }
+
+    fn set_pinned(&mut self, pinned: bool) {
+        if self.pinned != pinned {
+            self.pinned = pinned;
+            self.pin_item.set_text(self.pin_label());
+        }
+    }
+
+    // Disables the item and says so in its label while a connect attempt is in flight
+    pub fn set_connecting(&mut self, connecting: bool) {
+        self.item.set_enabled(self.connectable && !connecting);
+        if self.connecting != connecting {
+            self.connecting = connecting;
+            self.lost &= !connecting;
+            self.retrying = None;
+            self.stop_retry_item.set_enabled(false);
+            self.cancel_item.set_enabled(connecting);
+            self.item.set_text(self.label());
+        }
+    }
+
+    // Shows which attempt a connect in flight is on, after the first one failed
+    pub fn set_retrying(&mut self, attempt: u32, max_attempts: u32) {
+        if self.connecting {
+            self.retrying = Some((attempt, max_attempts));
+            self.stop_retry_item.set_enabled(true);
+            self.item.set_text(self.label());
+        }
+    }
+
+    // False for a click that follows the last accepted one too closely
+    pub fn accept_click(&mut self) -> bool {
+        let now = Instant::now();
+        if self.last_click.is_some_and(|last| now - last < CLICK_DEBOUNCE) {
+            return false;
+        }
+        self.last_click = Some(now);
+        true
+    }
+
+    pub fn is_lost(&self) -> bool {
+        self.lost
+    }
+
+    pub fn set_lost(&mut self, lost: bool) {
+        if self.lost != lost {
+            self.lost = lost;
+            self.item.set_text(self.label());
+        }
+    }
+
+    pub fn set_flaky(&mut self, flaky: bool) {
+        if self.flaky != flaky {
+            self.flaky = flaky;
+            self.item.set_text(self.label());
+        }
+    }
+
+    fn set_connectable(&mut self, connectable: bool) {
+        if self.connectable != connectable {
+            self.connectable = connectable;
+            self.item.set_text(self.label());
+        }
+    }
+
+    pub fn set_battery(&mut self, battery: Option<u8>) {
+        if self.battery != battery {
+            self.battery = battery;
+            self.item.set_text(self.label());
+        }
+    }
+
+    pub fn set_rssi(&mut self, rssi: Option<i16>) {
+        if self.rssi != rssi {
+            self.rssi = rssi;
+            self.item.set_text(self.label());
+        }
+    }
+
+    pub fn set_connected_for(&mut self, elapsed: Option<Duration>) {
+        let minutes = elapsed.map(|elapsed| elapsed.as_secs() / 60);
+        if self.connected_minutes != minutes {
+            self.connected_minutes = minutes;
+            self.item.set_text(self.label());
+        }
+    }
+
+    pub fn set_last_seen(&mut self, last_seen: Option<SystemTime>) {
+        self.last_seen = last_seen;
+        self.refresh_last_seen();
+    }
+
+    // Relative times go stale, so this runs whenever the menu may be about to open
+    pub fn refresh_last_seen(&mut self) {
+        let minutes = self
+            .last_seen
+            .map(|seen| seen.elapsed().unwrap_or_default().as_secs() / 60);
+        if self.seen_minutes_ago != minutes {
+            self.seen_minutes_ago = minutes;
+            self.item.set_text(self.label());
+        }
+    }
+
+    // Battery, signal and uptime only mean something while connected
+    pub fn clear_status(&mut self) {
+        self.set_battery(None);
+        self.set_rssi(None);
+        self.set_connected_for(None);
+    }
+}
+
+// e.g. "1h23m", or "<1m" right after connecting
+fn format_minutes(minutes: u64) -> String {
+    match (minutes / 60, minutes % 60) {
+        (0, 0) => "<1m".to_string(),
+        (0, minutes) => format!("{}m", minutes),
+        (hours, minutes) => format!("{}h{:02}m", hours, minutes),
+    }
+}
+
+fn format_age(minutes: u64) -> String {
+    match minutes {
+        0 => "just now".to_string(),
+        1..60 => format!("{}m ago", minutes),
+        60..1440 => format!("{}h ago", minutes / 60),
+        _ => format!("{}d ago", minutes / 1440),
+    }
+}
+
+// Every entry in display order, before splitting into pinned and the rest. Split out of
+// DeviceMenu so laying out can borrow the entries and the menus separately
+fn ordered_entries(
+    devices: &HashMap<MenuId, DeviceEntry>,
+    order: DeviceOrder,
+    connected_first: bool,
+) -> Vec<&DeviceEntry> {
+    let mut entries: Vec<&DeviceEntry> = devices.values().collect();
+    match order {
+        DeviceOrder::Alphabetical => entries.sort_by_cached_key(|entry| entry.name.to_lowercase()),
+        DeviceOrder::Enumeration => entries.sort_by_key(|entry| entry.position),
+    }
+    // Stable, so each half keeps the configured order
+    if connected_first {
+        entries.sort_by_key(|entry| !entry.shows_connected);
+    }
+    entries
+}
+
+fn summary_label(connected: usize, paired: usize) -> String {
+    format!("Connected: {} of {} paired", connected, paired)
+}
+
+fn copy_address_label(name: &str) -> String {
+    format!("{} (MAC address)", name)
+}
+
+// Keeps the device section of the tray menu in sync with the paired devices
+pub struct DeviceMenu {
+    menu: Menu,
+    copy_menu: Submenu,
+    // At the top of "Copy device ID", for copying every device at once
+    copy_table_item: MenuItem,
+    copy_json_item: MenuItem,
+    pin_menu: Submenu,
+    auto_connect_menu: Submenu,
+    test_menu: Submenu,
+    stop_retry_menu: Submenu,
+    cancel_menu: Submenu,
+    forget_menu: Submenu,
+    profile_menu: Submenu,
+    pub devices: HashMap<MenuId, DeviceEntry>,
+    // Stands in for the devices while they load, or says why they couldn't be listed
+    error_item: Option<MenuItem>,
+    // The error item is enabled, and a click on it lists the devices again
+    error_retries: bool,
+    aliases: HashMap<String, String>,
+    // Preferred RFCOMM service per device id, as a UUID string
+    connect_profiles: HashMap<String, String>,
+    order: DeviceOrder,
+    group_by_class: bool,
+    connected_first: bool,
+    // Device items in the order the last layout put them
+    laid_out: Vec<MenuId>,
+    // Class submenus currently in the menu, in display order
+    groups: Vec<Submenu>,
+    // Device ids shown at the top level, with everything else under all_menu
+    pinned: Vec<String>,
+    // Device ids connected as soon as they come into range
+    auto_connect: Vec<String>,
+    all_menu: Submenu,
+    // Between the pinned devices and the rest
+    pinned_separator: PredefinedMenuItem,
+    // Top-level items the device section takes up, not counting the error item
+    section_len: usize,
+    icons: ClassIcons,
+    // "Connected: N of M paired" at the very top; disabled, so clicks never reach the loop
+    summary_item: MenuItem,
+}
+
+impl DeviceMenu {
+    pub fn new(menu: Menu) -> Self {
+        let summary_item = MenuItem::new(summary_label(0, 0), false, None);
+        menu.insert(&summary_item, 0).or_log();
+        let copy_menu = Submenu::new("Copy device ID", true);
+        let copy_table_item = MenuItem::new("All devices as a table", true, None);
+        let copy_json_item = MenuItem::new("All devices as JSON", true, None);
+        copy_menu
+            .append_items(&[&copy_table_item, &copy_json_item, &PredefinedMenuItem::separator()])
+            .or_log();
+        Self {
+            menu,
+            copy_menu,
+            copy_table_item,
+            copy_json_item,
+            pin_menu: Submenu::new("Pin devices", true),
+            auto_connect_menu: Submenu::new("Auto-connect when in range", true),
+            test_menu: Submenu::new("Test connection", true),
+            stop_retry_menu: Submenu::new("Stop retrying", true),
+            cancel_menu: Submenu::new("Cancel connecting", true),
+            forget_menu: Submenu::new("Forget device", true),
+            profile_menu: Submenu::new("Connection profile", false),
+            devices: HashMap::new(),
+            error_item: None,
+            error_retries: false,
+            aliases: HashMap::new(),
+            connect_profiles: HashMap::new(),
+            order: DeviceOrder::default(),
+            group_by_class: false,
+            connected_first: false,
+            laid_out: Vec::new(),
+            groups: Vec::new(),
+            pinned: Vec::new(),
+            auto_connect: Vec::new(),
+            all_menu: Submenu::new("All devices", true),
+            pinned_separator: PredefinedMenuItem::separator(),
+            section_len: 0,
+            icons: ClassIcons::new(),
+            summary_item,
+        }
+    }
+
+    // Counts what the items show as connected, so it agrees with the marks next to them
+    pub fn update_summary(&self) {
+        let connected = self.devices.values().filter(|entry| entry.shows_connected).count();
+        let label = summary_label(connected, self.devices.len());
+        if self.summary_item.text() != label {
+            self.summary_item.set_text(label);
+        }
+    }
+
+    // Lists every device, for copying its id or address, below items copying all of them;
+    // the caller places it in the menu
+    pub fn copy_menu(&self) -> &Submenu {
+        &self.copy_menu
+    }
+
+    // Lists every device with a Pin or Unpin action; the caller places it in the menu
+    pub fn pin_menu(&self) -> &Submenu {
+        &self.pin_menu
+    }
+
+    // Lists every device with a check for auto-connecting it; the caller places it in the menu
+    pub fn auto_connect_menu(&self) -> &Submenu {
+        &self.auto_connect_menu
+    }
+
+    // Lists every device for a throwaway test connect; the caller places it in the menu
+    pub fn test_menu(&self) -> &Submenu {
+        &self.test_menu
+    }
+
+    // Lists every device, enabled while its connect is retrying; the caller places it in the
+    // menu
+    pub fn stop_retry_menu(&self) -> &Submenu {
+        &self.stop_retry_menu
+    }
+
+    // Lists every device, enabled while it's connecting; the caller places it in the menu
+    pub fn cancel_menu(&self) -> &Submenu {
+        &self.cancel_menu
+    }
+
+    // Lists every device for unpairing; the caller places it in the menu
+    pub fn forget_menu(&self) -> &Submenu {
+        &self.forget_menu
+    }
+
+    // Lists the devices with more than one service to connect to; the caller places it in
+    // the menu
+    pub fn profile_menu(&self) -> &Submenu {
+        &self.profile_menu
+    }
+
+    // Takes effect right away, for the next connect
+    pub fn set_connect_profiles(&mut self, connect_profiles: HashMap<String, String>) {
+        self.connect_profiles = connect_profiles;
+        for entry in self.devices.values_mut() {
+            entry.set_preferred_service(preferred_service(&self.connect_profiles, &entry.device_id));
+        }
+    }
+
+    // Pinned device ids, which no longer include devices that were unpaired
+    pub fn pinned(&self) -> &[String] {
+        &self.pinned
+    }
+
+    // Rearranges the device section right away
+    pub fn set_pinned(&mut self, pinned: Vec<String>) {
+        self.pinned = pinned;
+        self.layout();
+    }
+
+    // Only updates the checks; connecting is up to the caller
+    pub fn set_auto_connect(&mut self, auto_connect: Vec<String>) {
+        self.auto_connect = auto_connect;
+        for entry in self.devices.values() {
+            entry
+                .auto_connect_item
+                .set_checked(contains_device_id(&self.auto_connect, &entry.device_id.to_string()));
+        }
+    }
+
+    // Rearranges the device section right away
+    pub fn set_layout(&mut self, order: DeviceOrder, group_by_class: bool, connected_first: bool) {
+        self.order = order;
+        self.group_by_class = group_by_class;
+        self.connected_first = connected_first;
+        self.layout();
+    }
+
+    // With connected devices first, lays the section out again once a device connected or
+    // disconnected since the last layout. Items are moved, not rebuilt, so menu ids stay.
+    pub fn reorder_if_needed(&mut self) {
+        if !self.connected_first {
+            return;
+        }
+        let wanted = ordered_entries(&self.devices, self.order, self.connected_first);
+        if !wanted.iter().map(|entry| entry.item.id()).eq(self.laid_out.iter()) {
+            self.layout();
+        }
+    }
+
+    // Aliases take effect on the next sync
+    pub fn set_aliases(&mut self, aliases: HashMap<String, String>) {
+        self.aliases = aliases;
+    }
+
+    pub fn sync<P: BluetoothPlatform>(
+        &mut self,
+        devices: Result<Vec<PairedDevice>, BlueTrayError>,
+        manager: &mut ConnectionManager<P>,
+    ) {
+        let devices = match devices {
+            Ok(devices) => devices,
+            Err(BlueTrayError::RadioOff) => {
+                // Keep the devices around so they come back as they were once the radio is on
+                for entry in self.devices.values() {
+                    entry.item.set_enabled(false);
+                }
+                self.show_error("Bluetooth is off");
+                return;
+            }
+            // Whatever was listed last stays, still usable, in case the stack comes back
+            Err(e @ BlueTrayError::NotResponding(_)) => {
+                warn!(error = %e, "Bluetooth stopped responding");
+                self.show_retry("Bluetooth not responding - Retry");
+                return;
+            }
+            Err(e) => {
+                warn!(error = %e, "Failed to enumerate Bluetooth devices");
+                self.show_error("Could not list Bluetooth devices (is Bluetooth on?)");
+                return;
+            }
+        };
+        self.clear_error();
+
+        let paired_ids: Vec<String> = devices
+            .iter()
+            .map(|device| device.device_id.to_string())
+            .collect();
+        self.pinned.retain(|device_id| contains_device_id(&paired_ids, device_id));
+
+        // Drop items for devices that are no longer paired
+        let stale: Vec<MenuId> = self
+            .devices
+            .iter()
+            .filter(|(_, entry)| !contains_device_id(&paired_ids, &entry.device_id.to_string()))
+            .map(|(menu_id, _)| menu_id.clone())
+            .collect();
+        for menu_id in stale {
+            self.remove(&menu_id, manager);
+        }
+
+        // Add items for newly paired devices and update the existing ones in place
+        for (position, device) in devices.iter().enumerate() {
+            let device_id = device.device_id.clone();
+            let name = match get_by_device_id(&self.aliases, &device_id.to_string()) {
+                Some(alias) if !alias.is_empty() => alias.clone(),
+                _ => device.name.clone(),
+            };
+            // Items stay disabled while a connect attempt is in flight
+            let connecting = manager.is_connecting(&device_id.to_string());
+            let connected = manager.is_connected(&device_id.to_string()) || device.connected;
+            let preferred = preferred_service(&self.connect_profiles, &device_id);
+            if let Some(entry) = self.devices.values_mut().find(|entry| entry.is_device(&device_id)) {
+                entry.set_preferred_service(preferred);
+                entry.set_profiles(&self.profile_menu, device.profiles.clone());
+                entry.set_connectable(device.connectable);
+                entry.os_connected = device.connected;
+                entry.set_flaky(manager.is_flaky(&device_id.to_string()));
+                entry.item.set_enabled(device.connectable && !connecting);
+                // The item keeps its menu id; the connected mark follows the manager
+                entry.show_connected(connected);
+                entry.set_class(device.class, &self.icons);
+                entry.position = position;
+                entry.set_name(name);
+                continue;
+            }
+
+            let icon = self.icons.for_class(device.class, false);
+            let connected_icon = self.icons.for_class(device.class, true);
+            let shown = if connected { connected_icon.clone() } else { icon.clone() };
+            let item = IconMenuItem::new(&name, device.connectable && !connecting, shown, None);
+            let copy_id_item = MenuItem::new(&name, true, None);
+            self.copy_menu.append(&copy_id_item).or_log();
+            let copy_address_item = device.address.map(|_| {
+                let item = MenuItem::new(copy_address_label(&name), true, None);
+                self.copy_menu.append(&item).or_log();
+                item
+            });
+            let pin_item = MenuItem::new(format!("Pin {}", name), true, None);
+            self.pin_menu.append(&pin_item).or_log();
+            let auto_connect = contains_device_id(&self.auto_connect, &device_id.to_string());
+            let auto_connect_item = CheckMenuItem::new(&name, true, auto_connect, None);
+            self.auto_connect_menu.append(&auto_connect_item).or_log();
+            let test_item = MenuItem::new(&name, true, None);
+            self.test_menu.append(&test_item).or_log();
+            let stop_retry_item = MenuItem::new(&name, false, None);
+            self.stop_retry_menu.append(&stop_retry_item).or_log();
+            let cancel_item = MenuItem::new(&name, false, None);
+            self.cancel_menu.append(&cancel_item).or_log();
+            let forget_item = MenuItem::new(&name, true, None);
+            self.forget_menu.append(&forget_item).or_log();
+            let mut entry = DeviceEntry {
+                device_id,
+                kind: device.kind,
+                class: device.class,
+                name,
+                item,
+                icon,
+                connected_icon,
+                shows_connected: connected,
+                os_connected: device.connected,
+                address: device.address,
+                copy_id_item,
+                copy_address_item,
+                pin_item,
+                pinned: false,
+                auto_connect_item,
+                test_item,
+                forget_item,
+                profiles: Vec::new(),
+                profile_menu: None,
+                profile_items: Vec::new(),
+                preferred_service: preferred,
+                battery: None,
+                rssi: None,
+                connected_minutes: None,
+                last_seen: None,
+                seen_minutes_ago: None,
+                connecting: false,
+                retrying: None,
+                stop_retry_item,
+                cancel_item,
+                lost: false,
+                flaky: manager.is_flaky(&device.device_id.to_string()),
+                last_click: None,
+                connectable: device.connectable,
+                position,
+            };
+            entry.item.set_text(entry.label());
+            entry.set_profiles(&self.profile_menu, device.profiles.clone());
+            self.devices.insert(entry.item.id().clone(), entry);
+        }
+        self.profile_menu.set_enabled(self.devices.values().any(|entry| entry.profile_menu.is_some()));
+        self.layout();
+        // Otherwise an empty list looks the same as one that never loaded
+        if self.devices.is_empty() {
+            self.show_error("No paired devices");
+        }
+
+        info!(paired = self.devices.len(), "Device list refreshed");
+    }
+
+    // Drops a device that is no longer paired, along with any connection to it
+    pub fn remove_device<P: BluetoothPlatform>(&mut self, device_id: &HSTRING, manager: &mut ConnectionManager<P>) {
+        let menu_id = self
+            .devices
+            .iter()
+            .find(|(_, entry)| entry.is_device(device_id))
+            .map(|(menu_id, _)| menu_id.clone());
+        if let Some(menu_id) = menu_id {
+            self.remove(&menu_id, manager);
+        }
+    }
+
+    fn remove<P: BluetoothPlatform>(&mut self, menu_id: &MenuId, manager: &mut ConnectionManager<P>) {
+        if let Some(entry) = self.devices.remove(menu_id) {
+            manager.disconnect_device(&entry.device_id.to_string());
+            self.detach(&entry.item);
+            let _ = self.copy_menu.remove(&entry.copy_id_item);
+            if let Some(item) = &entry.copy_address_item {
+                let _ = self.copy_menu.remove(item);
+            }
+            let _ = self.pin_menu.remove(&entry.pin_item);
+            let _ = self.auto_connect_menu.remove(&entry.auto_connect_item);
+            let _ = self.test_menu.remove(&entry.test_item);
+            let _ = self.stop_retry_menu.remove(&entry.stop_retry_item);
+            let _ = self.cancel_menu.remove(&entry.cancel_item);
+            let _ = self.forget_menu.remove(&entry.forget_item);
+            if let Some(menu) = &entry.profile_menu {
+                let _ = self.profile_menu.remove(menu);
+            }
+            // Drops the class submenu if this was its last device
+            self.layout();
+        }
+    }
+
+    // Puts every device item back into the section in the configured order. Pinned devices
+    // come first at the top level; once there are any, the rest go below a separator in an
+    // "All devices" submenu. Items are detached first since they may be moving between menus.
+    fn layout(&mut self) {
+        for entry in self.devices.values_mut() {
+            entry.set_pinned(contains_device_id(&self.pinned, &entry.device_id.to_string()));
+        }
+        let entries = ordered_entries(&self.devices, self.order, self.connected_first);
+        let laid_out = entries.iter().map(|entry| entry.item.id().clone()).collect();
+
+        for entry in &entries {
+            self.detach(&entry.item);
+        }
+        for group in self.groups.drain(..) {
+            let _ = self.menu.remove(&group);
+            let _ = self.all_menu.remove(&group);
+        }
+        let _ = self.menu.remove(&self.all_menu);
+        let _ = self.menu.remove(&self.pinned_separator);
+
+        let (pinned, rest): (Vec<_>, Vec<_>) = entries
+            .into_iter()
+            .partition(|entry| entry.pinned);
+        let mut position = DEVICE_SECTION_START;
+        for entry in &pinned {
+            self.menu.insert(&entry.item, position).or_log();
+            position += 1;
+        }
+        let nested = !pinned.is_empty() && !rest.is_empty();
+        if nested {
+            self.menu.insert(&self.pinned_separator, position).or_log();
+            self.menu.insert(&self.all_menu, position + 1).or_log();
+            position += 2;
+        }
+
+        // Either straight into the section or into the "All devices" submenu
+        let mut place = |item: &dyn IsMenuItem| {
+            if nested {
+                self.all_menu.append(item).or_log();
+            } else {
+                self.menu.insert(item, position).or_log();
+                position += 1;
+            }
+        };
+        if self.group_by_class {
+            for class in DeviceClass::ALL {
+                let members: Vec<_> = rest.iter().filter(|entry| entry.class == class).collect();
+                if members.is_empty() {
+                    continue;
+                }
+                let group = Submenu::new(class.label(), true);
+                for entry in members {
+                    group.append(&entry.item).or_log();
+                }
+                place(&group);
+                self.groups.push(group);
+            }
+        } else {
+            for entry in &rest {
+                place(&entry.item);
+            }
+        }
+        self.section_len = position - DEVICE_SECTION_START;
+        self.laid_out = laid_out;
+    }
+
+    // Removes the item from wherever it currently sits
+    fn detach(&self, item: &IconMenuItem) {
+        let _ = self.menu.remove(item);
+        let _ = self.all_menu.remove(item);
+        for group in &self.groups {
+            let _ = group.remove(item);
+        }
+    }
+
+    // What a click in the "Copy device ID" submenu should copy, with a description of it
+    pub fn copy_target(&self, menu_id: &MenuId) -> Option<(String, String)> {
+        self.devices.values().find_map(|entry| {
+            if entry.copy_id_item.id() == menu_id {
+                return Some((entry.device_id.to_string(), format!("ID of {}", entry.name)));
+            }
+            let address = entry.address?;
+            let item = entry.copy_address_item.as_ref()?;
+            (item.id() == menu_id).then(|| (format_address(address), format!("MAC address of {}", entry.name)))
+        })
+    }
+
+    // How a click on one of the "All devices" items wants the listing copied
+    pub fn copy_all_target(&self, menu_id: &MenuId) -> Option<ListingFormat> {
+        if self.copy_table_item.id() == menu_id {
+            Some(ListingFormat::Table)
+        } else if self.copy_json_item.id() == menu_id {
+            Some(ListingFormat::Json)
+        } else {
+            None
+        }
+    }
+
+    // The device a click in the "Forget device" submenu is about
+    pub fn forget_target(&self, menu_id: &MenuId) -> Option<(HSTRING, String)> {
+        self.devices
+            .values()
+            .find(|entry| entry.forget_item.id() == menu_id)
+            .map(|entry| (entry.device_id.clone(), entry.name.clone()))
+    }
+
+    // The device a click in the "Test connection" submenu is about
+    pub fn test_target(&self, menu_id: &MenuId) -> Option<&DeviceEntry> {
+        self.devices.values().find(|entry| entry.test_item.id() == menu_id)
+    }
+
+    // The device a click in the "Cancel connecting" submenu is about
+    pub fn cancel_target(&self, menu_id: &MenuId) -> Option<String> {
+        self.devices
+            .values()
+            .find(|entry| entry.cancel_item.id() == menu_id)
+            .map(|entry| entry.device_id.to_string())
+    }
+
+    // The device a click in the "Stop retrying" submenu is about
+    pub fn stop_retry_target(&self, menu_id: &MenuId) -> Option<String> {
+        self.devices
+            .values()
+            .find(|entry| entry.stop_retry_item.id() == menu_id)
+            .map(|entry| entry.device_id.to_string())
+    }
+
+    // The device a click in the "Pin devices" submenu is about
+    pub fn pin_target(&self, menu_id: &MenuId) -> Option<String> {
+        self.devices
+            .values()
+            .find(|entry| entry.pin_item.id() == menu_id)
+            .map(|entry| entry.device_id.to_string())
+    }
+
+    // The device a click in the "Auto-connect when in range" submenu is about
+    pub fn auto_connect_target(&self, menu_id: &MenuId) -> Option<String> {
+        self.devices
+            .values()
+            .find(|entry| entry.auto_connect_item.id() == menu_id)
+            .map(|entry| entry.device_id.to_string())
+    }
+
+    // The device and service a click in the "Connection profile" submenu picks
+    pub fn profile_target(&self, menu_id: &MenuId) -> Option<(String, Option<GUID>)> {
+        self.devices.values().find_map(|entry| {
+            let (uuid, _) = entry.profile_items.iter().find(|(_, item)| item.id() == menu_id)?;
+            Some((entry.device_id.to_string(), *uuid))
+        })
+    }
+
+    pub fn find(&self, device_id: &HSTRING) -> Option<&DeviceEntry> {
+        self.devices.values().find(|entry| entry.is_device(device_id))
+    }
+
+    pub fn find_mut(&mut self, device_id: &HSTRING) -> Option<&mut DeviceEntry> {
+        self.devices.values_mut().find(|entry| entry.is_device(device_id))
+    }
+
+    // Fills in the saved last seen times, e.g. for devices that just appeared in the menu
+    pub fn show_last_seen(&mut self, saved_state: &SavedState) {
+        for entry in self.devices.values_mut() {
+            entry.set_last_seen(saved_state.last_seen(&entry.device_id.to_string()));
+        }
+    }
+
+    pub fn refresh_last_seen(&mut self) {
+        for entry in self.devices.values_mut() {
+            entry.refresh_last_seen();
+        }
+    }
+
+    pub fn name(&self, device_id: &HSTRING) -> String {
+        self.find(device_id)
+            .map(|entry| entry.name.clone())
+            .unwrap_or_else(|| device_id.to_string())
+    }
+
+    // Until the first enumeration comes back, so the tray can show up right away
+    pub fn show_loading(&mut self) {
+        self.show_error("Loading devices…");
+    }
+
+    fn show_error(&mut self, message: &str) {
+        self.clear_error();
+        let item = MenuItem::new(message, false, None);
+        self.menu
+            .insert(&item, DEVICE_SECTION_START + self.section_len)
+            .or_log();
+        self.error_item = Some(item);
+    }
+
+    // Like show_error, with the item clickable to try listing again
+    fn show_retry(&mut self, message: &str) {
+        self.show_error(message);
+        if let Some(item) = &self.error_item {
+            item.set_enabled(true);
+        }
+        self.error_retries = true;
+    }
+
+    fn clear_error(&mut self) {
+        if let Some(item) = self.error_item.take() {
+            let _ = self.menu.remove(&item);
+        }
+        self.error_retries = false;
+    }
+
+    // Whether the click was on the item offering to list the devices again
+    pub fn is_retry(&self, menu_id: &MenuId) -> bool {
+        self.error_retries && self.error_item.as_ref().is_some_and(|item| item.id() == menu_id)
+    }
+}
+
+// The service picked for a device in the config, or the one for every device under "*".
+// Entries that don't parse as a UUID are ignored, so a typo falls back to the first service
+pub fn preferred_service(connect_profiles: &HashMap<String, String>, device_id: &HSTRING) -> Option<GUID> {
+    let uuid = get_by_device_id(connect_profiles, &device_id.to_string())
+        .or_else(|| connect_profiles.get(ALL_DEVICES_PROFILE))?;
+    GUID::try_from(uuid.as_str())
+        .inspect_err(|_| warn!(%device_id, %uuid, "Ignoring connect profile that isn't a UUID"))
+        .ok()
+}
+
+// The "Bluetooth adapter" submenu, with the adapter in use checked. Only enabled when
+// there's a choice to make.
+pub struct AdapterMenu {
+    menu: Submenu,
+    // None is the system default
+    items: Vec<(Option<u64>, CheckMenuItem)>,
+    selected: Option<u64>,
+}
+
+impl AdapterMenu {
+    pub fn new(selected: Option<u64>) -> Self {
+        Self {
+            menu: Submenu::new("Bluetooth adapter", false),
+            items: Vec::new(),
+            selected,
+        }
+    }
+
+    pub fn menu(&self) -> &Submenu {
+        &self.menu
+    }
+
+    pub fn show_adapters(&mut self, adapters: Result<Vec<AdapterInfo>, BlueTrayError>) {
+        for (_, item) in self.items.drain(..) {
+            let _ = self.menu.remove(&item);
+        }
+        let adapters = adapters.unwrap_or_else(|e| {
+            warn!(error = %e, "Failed to list Bluetooth adapters");
+            Vec::new()
+        });
+
+        let choices = std::iter::once((None, "System default".to_string())).chain(
+            adapters
+                .iter()
+                .map(|adapter| (Some(adapter.address), format!("{} ({})", adapter.name, format_address(adapter.address)))),
+        );
+        for (address, label) in choices {
+            let item = CheckMenuItem::new(label, true, address == self.selected, None);
+            self.menu.append(&item).or_log();
+            self.items.push((address, item));
+        }
+        // A configured adapter stays switchable even if it's the only one left
+        self.menu.set_enabled(adapters.len() > 1 || self.selected.is_some());
+    }
+
+    // The adapter a click picked, None being the system default
+    pub fn target(&self, menu_id: &MenuId) -> Option<Option<u64>> {
+        self.items.iter().find(|(_, item)| item.id() == menu_id).map(|(address, _)| *address)
+    }
+
+    pub fn set_selected(&mut self, selected: Option<u64>) {
+        self.selected = selected;
+        for (address, item) in &self.items {
+            item.set_checked(*address == selected);
+        }
+    }
+}
+
+// The "Pair new device…" submenu: a scan action followed by whatever the last scan found
+pub struct PairMenu {
+    menu: Submenu,
+    scan_item: MenuItem,
+    // Says what's going on when there are no devices to list
+    status_item: MenuItem,
+    devices: Vec<(MenuItem, UnpairedDevice)>,
+}
+
+impl PairMenu {
+    pub fn new() -> Self {
+        let menu = Submenu::new("Pair new device…", true);
+        let scan_item = MenuItem::new("Scan for devices", true, None);
+        menu.append(&scan_item).or_log();
+        Self {
+            menu,
+            scan_item,
+            status_item: MenuItem::new("", false, None),
+            devices: Vec::new(),
+        }
+    }
+
+    pub fn menu(&self) -> &Submenu {
+        &self.menu
+    }
+
+    pub fn is_scan(&self, menu_id: &MenuId) -> bool {
+        self.scan_item.id() == menu_id
+    }
+
+    pub fn set_scanning(&mut self) {
+        self.clear();
+        self.scan_item.set_enabled(false);
+        self.show_status("Scanning…");
+    }
+
+    pub fn show_results(&mut self, devices: Result<Vec<UnpairedDevice>, BlueTrayError>) {
+        self.clear();
+        self.scan_item.set_enabled(true);
+        match devices {
+            Ok(devices) if devices.is_empty() => self.show_status("No new devices found"),
+            Ok(devices) => {
+                for device in devices {
+                    let item = MenuItem::new(&device.name, true, None);
+                    self.menu.append(&item).or_log();
+                    self.devices.push((item, device));
+                }
+            }
+            Err(e) => {
+                warn!(error = %e, "Failed to scan for unpaired devices");
+                self.show_status("Scan failed");
+            }
+        }
+    }
+
+    // The device a click is about; its item stays disabled until pairing finishes
+    pub fn start_pairing(&self, menu_id: &MenuId) -> Option<UnpairedDevice> {
+        let (item, device) = self.devices.iter().find(|(item, _)| item.id() == menu_id)?;
+        item.set_enabled(false);
+        Some(device.clone())
+    }
+
+    // A paired device moves to the device list; one that failed can be tried again
+    pub fn finish_pairing(&mut self, device_id: &HSTRING, paired: bool) {
+        let Some(index) = self.devices.iter().position(|(_, device)| device.device_id == *device_id) else {
+            return;
+        };
+        if paired {
+            let (item, _) = self.devices.remove(index);
+            let _ = self.menu.remove(&item);
+        } else {
+            self.devices[index].0.set_enabled(true);
+        }
+    }
+
+    fn show_status(&self, text: &str) {
+        self.status_item.set_text(text);
+        self.menu.append(&self.status_item).or_log();
+    }
+
+    fn clear(&mut self) {
+        let _ = self.menu.remove(&self.status_item);
+        for (item, _) in self.devices.drain(..) {
+            let _ = self.menu.remove(&item);
+        }
+    }
+}
+
+// Refills the History submenu, newest entry first
+pub fn fill_history_menu(menu: &Submenu, history: &History) {
+    while menu.remove_at(0).is_some() {}
+    if history.entries.is_empty() {
+        menu.append(&MenuItem::new("No connections yet", false, None)).or_log();
+        return;
+    }
+    for entry in history.entries.iter().rev() {
+        menu.append(&MenuItem::new(entry.label(), false, None)).or_log();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::platform::mock::{connect, paired, tray_menu, MockPlatform};
+
+    #[tokio::test]
+    async fn connected_device_stays_marked_across_a_refresh() {
+        let mut device_menu = DeviceMenu::new(tray_menu());
+        let mut manager = ConnectionManager::new(MockPlatform::default());
+        let device_id = HSTRING::from("device-a");
+        device_menu.sync(Ok(vec![paired("device-a")]), &mut manager);
+        connect(&mut manager, &device_id).await;
+
+        let menu_id = device_menu.find(&device_id).unwrap().item.id().clone();
+        // As if the item had gone out of step with the manager
+        device_menu.find_mut(&device_id).unwrap().show_connected(false);
+        device_menu.sync(Ok(vec![paired("device-a"), paired("device-b")]), &mut manager);
+
+        let entry = device_menu.find(&device_id).unwrap();
+        assert_eq!(entry.item.id(), &menu_id);
+        assert!(entry.shows_connected());
+        assert!(device_menu.devices.contains_key(&menu_id));
+        assert!(manager.is_connected("device-a"));
+    }
+
+    #[tokio::test]
+    async fn refresh_drops_and_disconnects_unpaired_devices() {
+        let mut device_menu = DeviceMenu::new(tray_menu());
+        let mut manager = ConnectionManager::new(MockPlatform::default());
+        let device_id = HSTRING::from("device-a");
+        device_menu.sync(Ok(vec![paired("device-a")]), &mut manager);
+        connect(&mut manager, &device_id).await;
+
+        device_menu.sync(Ok(vec![paired("device-b")]), &mut manager);
+
+        assert!(device_menu.find(&device_id).is_none());
+        assert!(!manager.is_connected("device-a"));
+        assert_eq!(*manager.platform().closed.borrow(), vec![1]);
+    }
+
+    #[tokio::test]
+    async fn connected_devices_move_up_and_keep_their_items() {
+        let mut device_menu = DeviceMenu::new(tray_menu());
+        let mut manager = ConnectionManager::new(MockPlatform::default());
+        device_menu.set_layout(DeviceOrder::Alphabetical, false, true);
+        device_menu.sync(Ok(vec![paired("device-a"), paired("device-b")]), &mut manager);
+        let id = |device_menu: &DeviceMenu, device_id: &str| {
+            device_menu.find(&HSTRING::from(device_id)).unwrap().item.id().clone()
+        };
+        let (a, b) = (id(&device_menu, "device-a"), id(&device_menu, "device-b"));
+        assert_eq!(device_menu.laid_out, vec![a.clone(), b.clone()]);
+
+        device_menu.find_mut(&HSTRING::from("device-b")).unwrap().show_connected(true);
+        device_menu.reorder_if_needed();
+
+        assert_eq!(device_menu.laid_out, vec![b.clone(), a.clone()]);
+        assert_eq!(id(&device_menu, "device-b"), b);
+    }
+
+    #[test]
+    fn not_responding_keeps_the_devices_and_offers_a_retry() {
+        let mut device_menu = DeviceMenu::new(tray_menu());
+        let mut manager = ConnectionManager::new(MockPlatform::default());
+        device_menu.sync(Ok(vec![paired("device-a")]), &mut manager);
+
+        device_menu.sync(Err(BlueTrayError::NotResponding(Duration::from_secs(15))), &mut manager);
+
+        let retry = device_menu.error_item.as_ref().unwrap().id().clone();
+        assert!(device_menu.is_retry(&retry));
+        assert!(device_menu.find(&HSTRING::from("device-a")).is_some());
+
+        device_menu.sync(Ok(Vec::new()), &mut manager);
+
+        assert!(!device_menu.is_retry(&retry));
+        assert_eq!(device_menu.error_item.as_ref().unwrap().text(), "No paired devices");
+    }
+
+    #[tokio::test]
+    async fn an_empty_listing_says_so_instead_of_offering_a_retry() {
+        let mut device_menu = DeviceMenu::new(tray_menu());
+        let mut manager = ConnectionManager::new(MockPlatform::default());
+        device_menu.show_loading();
+
+        let devices = manager.platform().list_paired_devices().await;
+        device_menu.sync(devices, &mut manager);
+
+        let item = device_menu.error_item.as_ref().unwrap();
+        assert_eq!(item.text(), "No paired devices");
+        assert!(!item.is_enabled());
+        assert!(!device_menu.is_retry(item.id()));
+        assert!(device_menu.devices.is_empty());
+    }
+
+    #[test]
+    fn config_ids_match_however_they_are_cased() {
+        let mut device_menu = DeviceMenu::new(tray_menu());
+        let mut manager = ConnectionManager::new(MockPlatform::default());
+        device_menu.set_aliases(HashMap::from([("DEVICE-A".to_string(), "Headphones".to_string())]));
+        device_menu.set_pinned(vec!["Device-A ".to_string()]);
+        device_menu.sync(Ok(vec![paired("device-a"), paired("device-b")]), &mut manager);
+
+        let entry = device_menu.find(&HSTRING::from("device-a")).unwrap();
+        assert_eq!(entry.name, "Headphones");
+        assert!(entry.pinned);
+        assert_eq!(device_menu.pinned(), ["Device-A "]);
+    }
+
+    #[test]
+    fn device_profile_wins_over_the_one_for_all_devices() {
+        let serial = "00001101-0000-1000-8000-00805f9b34fb";
+        let hands_free = "0000111e-0000-1000-8000-00805f9b34fb";
+        let connect_profiles = HashMap::from([
+            ("*".to_string(), serial.to_string()),
+            ("device-a".to_string(), hands_free.to_string()),
+        ]);
+
+        let preferred = |device_id: &str| preferred_service(&connect_profiles, &HSTRING::from(device_id));
+        assert_eq!(preferred("device-a"), Some(GUID::try_from(hands_free).unwrap()));
+        assert_eq!(preferred("device-b"), Some(GUID::try_from(serial).unwrap()));
+    }
+}
@@ -0,0 +1,88 @@
+use tracing::warn;
+use windows::core::{Error, HSTRING};
+use windows::Data::Xml::Dom::XmlDocument;
+use windows::UI::Notifications::{ToastNotification, ToastNotificationManager};
+use windows::Win32::UI::WindowsAndMessaging::{
+    MessageBoxW, IDYES, MB_ICONERROR, MB_ICONQUESTION, MB_OK, MB_SETFOREGROUND, MB_YESNO,
+};
+
+// Unpackaged apps can show toasts under any id; it's what Windows shows as the sender
+const APP_USER_MODEL_ID: &str = "BlueTray";
+
+// Shows toast notifications for connection results, unless the user silenced them
+pub struct Notifier {
+    pub enabled: bool,
+}
+
+impl Notifier {
+    pub fn new() -> Self {
+        Self { enabled: true }
+    }
+
+    pub fn info(&self, title: &str, message: &str) {
+        self.show(title, message);
+    }
+
+    pub fn error(&self, title: &str, message: &str) {
+        self.show(&format!("⚠ {}", title), message);
+    }
+
+    // For errors the user has to act on. Shown even with notifications off, since a toast
+    // is easy to miss and this is not something to stay silent about.
+    pub fn alert(&self, title: &str, message: &str) {
+        show_message_box(title, message);
+    }
+
+    fn show(&self, title: &str, message: &str) {
+        if !self.enabled {
+            return;
+        }
+        if let Err(e) = show_toast(title, message) {
+            warn!(error = %e, "Failed to show notification");
+        }
+    }
+}
+
+fn show_toast(title: &str, message: &str) -> Result<(), Error> {
+    let xml = format!(
+        "<toast><visual><binding template=\"ToastGeneric\"><text>{}</text><text>{}</text></binding></visual></toast>",
+        escape_xml(title),
+        escape_xml(message),
+    );
+
+    let document = XmlDocument::new()?;
+    document.LoadXml(&HSTRING::from(xml))?;
+    let toast = ToastNotification::CreateToastNotification(&document)?;
+    ToastNotificationManager::CreateToastNotifierWithId(&HSTRING::from(APP_USER_MODEL_ID))?.Show(&toast)
+}
+
+// A native error dialog on its own thread, so waiting for the user to close it doesn't
+// stall the event loop. Join the handle to wait for it, e.g. before exiting.
+pub fn show_message_box(title: &str, message: &str) -> std::thread::JoinHandle<()> {
+    let title = HSTRING::from(title);
+    let message = HSTRING::from(message);
+    std::thread::spawn(move || unsafe {
+        MessageBoxW(None, &message, &title, MB_OK | MB_ICONERROR | MB_SETFOREGROUND);
+    })
+}
+
+// A yes/no question that blocks until answered, so only call it off the event loop
+pub fn ask(title: &str, message: &str) -> bool {
+    let answer = unsafe {
+        MessageBoxW(
+            None,
+            &HSTRING::from(message),
+            &HSTRING::from(title),
+            MB_YESNO | MB_ICONQUESTION | MB_SETFOREGROUND,
+        )
+    };
+    answer == IDYES
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
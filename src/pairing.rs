@@ -0,0 +1,92 @@
+use tracing::{info, warn};
+use windows::core::HSTRING;
+use windows::Devices::Bluetooth::{BluetoothDevice, BluetoothLEDevice};
+use windows::Devices::Enumeration::{
+    DeviceInformation, DeviceInformationCustomPairing, DevicePairingKinds, DevicePairingRequestedEventArgs,
+    DevicePairingResultStatus, DeviceUnpairingResultStatus,
+};
+use windows::Foundation::TypedEventHandler;
+
+use crate::error::BlueTrayError;
+use crate::notifications::{ask, show_message_box};
+
+// A nearby device that isn't paired yet
+#[derive(Clone)]
+pub struct UnpairedDevice {
+    pub device_id: HSTRING,
+    pub name: String,
+}
+
+// What Windows currently knows to be in range, classic and BLE. Devices without a name
+// are left out since there'd be no telling them apart in the menu.
+pub async fn find_unpaired_devices() -> Result<Vec<UnpairedDevice>, BlueTrayError> {
+    let mut devices = Vec::new();
+    for selector in [
+        BluetoothDevice::GetDeviceSelectorFromPairingState(false)?,
+        BluetoothLEDevice::GetDeviceSelectorFromPairingState(false)?,
+    ] {
+        for info in DeviceInformation::FindAllAsyncAqsFilter(&selector)?.await? {
+            let (Ok(device_id), Ok(name)) = (info.Id(), info.Name()) else {
+                continue;
+            };
+            if !name.is_empty() && info.Pairing().and_then(|pairing| pairing.CanPair()).unwrap_or(false) {
+                devices.push(UnpairedDevice { device_id, name: name.to_string() });
+            }
+        }
+    }
+    Ok(devices)
+}
+
+// Pairs with the device, asking the user through dialogs whenever the device wants a PIN
+// confirmed or shown. Devices that need a PIN typed in can't be paired from here.
+pub async fn pair_device(device: &UnpairedDevice) -> Result<(), BlueTrayError> {
+    let info = DeviceInformation::CreateFromIdAsync(&device.device_id)?.await?;
+    let custom = info.Pairing()?.Custom()?;
+    let name = device.name.clone();
+    let token = custom.PairingRequested(&TypedEventHandler::<
+        DeviceInformationCustomPairing,
+        DevicePairingRequestedEventArgs,
+    >::new(move |_, args| {
+        let Ok(args) = args.ok() else {
+            return Ok(());
+        };
+        match args.PairingKind()? {
+            DevicePairingKinds::ConfirmOnly => args.Accept()?,
+            DevicePairingKinds::DisplayPin => {
+                let _ = show_message_box("Pair new device", &format!("Enter {} on {}", args.Pin()?, name));
+                args.Accept()?;
+            }
+            // Windows waits on this handler, so blocking on the answer is fine
+            DevicePairingKinds::ConfirmPinMatch => {
+                if ask("Pair new device", &format!("Does {} show {}?", name, args.Pin()?)) {
+                    args.Accept()?;
+                }
+            }
+            kind => warn!(?kind, "Pairing needs input bluetray can't provide"),
+        }
+        Ok(())
+    }))?;
+
+    let kinds = DevicePairingKinds::ConfirmOnly | DevicePairingKinds::DisplayPin | DevicePairingKinds::ConfirmPinMatch;
+    let result = custom.PairAsync(kinds)?.await;
+    let _ = custom.RemovePairingRequested(token);
+    match result?.Status()? {
+        DevicePairingResultStatus::Paired | DevicePairingResultStatus::AlreadyPaired => {
+            info!(device_id = %device.device_id, name = %device.name, "Paired");
+            Ok(())
+        }
+        status => Err(BlueTrayError::PairingFailed(format!("{:?}", status))),
+    }
+}
+
+// Removes the pairing; Windows drops whatever link is left along with it
+pub async fn unpair_device(device_id: &HSTRING) -> Result<(), BlueTrayError> {
+    let info = DeviceInformation::CreateFromIdAsync(device_id)?.await?;
+    match info.Pairing()?.UnpairAsync()?.await?.Status()? {
+        DeviceUnpairingResultStatus::Unpaired | DeviceUnpairingResultStatus::AlreadyUnpaired => {
+            info!(%device_id, "Unpaired");
+            Ok(())
+        }
+        status => Err(BlueTrayError::UnpairingFailed(format!("{:?}", status))),
+    }
+}
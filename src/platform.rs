@@ -0,0 +1,57 @@
+use std::future::Future;
+
+use ::windows::core::{Error, GUID, HSTRING};
+
+use crate::bluetooth::{DeviceKind, PairedDevice};
+use crate::error::BlueTrayError;
+
+#[cfg(windows)]
+mod windows;
+
+#[cfg(windows)]
+pub use self::windows::WindowsPlatform;
+
+// Stand-in used by the tests
+#[cfg(test)]
+pub mod mock;
+
+// Everything the app needs from the OS Bluetooth stack, so the manager and the UI don't
+// depend on one platform and can be tested without a Bluetooth radio. Only Windows is
+// implemented for now; device ids and paired devices still carry WinRT types.
+pub trait BluetoothPlatform {
+    type Link;
+
+    fn list_paired_devices(&self) -> impl Future<Output = Result<Vec<PairedDevice>, BlueTrayError>> + Send;
+
+    // Whether the OS still considers the device connected
+    fn is_connected(&self, device_id: &HSTRING) -> impl Future<Output = bool> + Send;
+
+    // One attempt; connection::connect_with_retry does the retrying. preferred_service
+    // picks the RFCOMM service of a classic device; without it, or when the device doesn't
+    // offer it, the first service is used.
+    fn connect(
+        &self,
+        device_id: &HSTRING,
+        kind: DeviceKind,
+        preferred_service: Option<GUID>,
+    ) -> impl Future<Output = Result<Self::Link, BlueTrayError>> + Send;
+
+    fn disconnect(&self, link: &Self::Link) -> Result<(), Error>;
+
+    // Doesn't borrow the link, so it can be awaited without holding whatever owns it
+    fn send(
+        &self,
+        device_id: &str,
+        link: &Self::Link,
+        data: &[u8],
+    ) -> impl Future<Output = Result<(), BlueTrayError>> + Send + use<Self>;
+
+    // One line about the link for diagnostics, e.g. its addresses
+    fn describe(&self, link: &Self::Link) -> String;
+
+    // Battery percentage, for devices that report one
+    fn battery(&self, device_id: &HSTRING) -> impl Future<Output = Option<u8>> + Send;
+
+    // Signal strength in dBm, for devices that report one
+    fn signal_strength(&self, device_id: &HSTRING) -> impl Future<Output = Option<i16>> + Send;
+}
@@ -0,0 +1,111 @@
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::future::Future;
+
+use ::windows::core::{Error, GUID, HSTRING};
+use tray_icon::menu::{Menu, MenuItem, PredefinedMenuItem};
+
+use super::BluetoothPlatform;
+use crate::bluetooth::{DeviceClass, DeviceKind, PairedDevice};
+use crate::connection::ConnectionManager;
+use crate::error::BlueTrayError;
+
+// Link and bytes for every send
+type SentLog = Vec<(u32, Vec<u8>)>;
+
+// Hands out numbered links and remembers which ones were closed and what was sent. Clones
+// share all of it, like copies of the real platform share the OS stack.
+#[derive(Clone, Default)]
+pub struct MockPlatform {
+    next_link: Rc<Cell<u32>>,
+    pub closed: Rc<RefCell<Vec<u32>>>,
+    pub sent: Rc<RefCell<SentLog>>,
+    // Connects time out while this is above zero, counting down with each one
+    pub failures: Rc<Cell<u32>>,
+}
+
+impl BluetoothPlatform for MockPlatform {
+    type Link = u32;
+
+    fn list_paired_devices(&self) -> impl Future<Output = Result<Vec<PairedDevice>, BlueTrayError>> + Send {
+        std::future::ready(Ok(Vec::new()))
+    }
+
+    fn is_connected(&self, _device_id: &HSTRING) -> impl Future<Output = bool> + Send {
+        std::future::ready(true)
+    }
+
+    fn connect(
+        &self,
+        _device_id: &HSTRING,
+        _kind: DeviceKind,
+        _preferred_service: Option<GUID>,
+    ) -> impl Future<Output = Result<u32, BlueTrayError>> + Send {
+        if self.failures.get() > 0 {
+            self.failures.set(self.failures.get() - 1);
+            return std::future::ready(Err(BlueTrayError::ConnectTimeout(Default::default())));
+        }
+        let link = self.next_link.get() + 1;
+        self.next_link.set(link);
+        std::future::ready(Ok(link))
+    }
+
+    fn disconnect(&self, link: &u32) -> Result<(), Error> {
+        self.closed.borrow_mut().push(*link);
+        Ok(())
+    }
+
+    fn send(
+        &self,
+        _device_id: &str,
+        link: &u32,
+        data: &[u8],
+    ) -> impl Future<Output = Result<(), BlueTrayError>> + Send + use<> {
+        self.sent.borrow_mut().push((*link, data.to_vec()));
+        std::future::ready(Ok(()))
+    }
+
+    fn describe(&self, link: &u32) -> String {
+        format!("mock link {}", link)
+    }
+
+    fn battery(&self, _device_id: &HSTRING) -> impl Future<Output = Option<u8>> + Send {
+        std::future::ready(None)
+    }
+
+    fn signal_strength(&self, _device_id: &HSTRING) -> impl Future<Output = Option<i16>> + Send {
+        std::future::ready(None)
+    }
+}
+
+// Runs a connect through the manager the way the event loop does
+pub async fn connect(manager: &mut ConnectionManager<MockPlatform>, device_id: &HSTRING) {
+    manager.begin_connect(device_id, device_id.to_string());
+    let link = manager
+        .platform()
+        .connect(device_id, DeviceKind::Classic, None)
+        .await;
+    manager.connect_device(device_id, link).unwrap();
+}
+
+// A connectable classic device named after its id
+pub fn paired(device_id: &str) -> PairedDevice {
+    PairedDevice {
+        device_id: HSTRING::from(device_id),
+        name: device_id.to_string(),
+        kind: DeviceKind::Classic,
+        class: DeviceClass::Other,
+        address: None,
+        connected: false,
+        connectable: true,
+        profiles: Vec::new(),
+    }
+}
+
+// A tray menu with the items that come before the device section
+pub fn tray_menu() -> Menu {
+    let menu = Menu::new();
+    menu.append(&MenuItem::new("About", true, None)).unwrap();
+    menu.append(&PredefinedMenuItem::separator()).unwrap();
+    menu
+}
@@ -0,0 +1,114 @@
+use std::future::Future;
+use std::time::Duration;
+
+use windows::core::{Error, GUID, HSTRING};
+use windows::Storage::Streams::DataWriter;
+
+use super::BluetoothPlatform;
+use crate::bluetooth::{
+    connect_device, get_paired_bluetooth_devices, is_device_connected, read_battery, read_rssi, DeviceKind,
+    PairedDevice,
+};
+use crate::connection::DeviceConnection;
+use crate::error::BlueTrayError;
+use crate::config::Config;
+
+// The real thing: RFCOMM sockets and GATT sessions through WinRT
+#[derive(Clone, Copy)]
+pub struct WindowsPlatform {
+    pub connect_timeout: Duration,
+    // Address of the adapter whose devices are listed; all of them when None
+    pub adapter: Option<u64>,
+}
+
+impl WindowsPlatform {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            connect_timeout: config.connect_timeout(),
+            adapter: config.adapter_address(),
+        }
+    }
+}
+
+impl BluetoothPlatform for WindowsPlatform {
+    type Link = DeviceConnection;
+
+    fn list_paired_devices(&self) -> impl Future<Output = Result<Vec<PairedDevice>, BlueTrayError>> + Send {
+        get_paired_bluetooth_devices(self.adapter)
+    }
+
+    fn is_connected(&self, device_id: &HSTRING) -> impl Future<Output = bool> + Send {
+        let device_id = device_id.clone();
+        async move { is_device_connected(&device_id).await }
+    }
+
+    fn connect(
+        &self,
+        device_id: &HSTRING,
+        kind: DeviceKind,
+        preferred_service: Option<GUID>,
+    ) -> impl Future<Output = Result<DeviceConnection, BlueTrayError>> + Send {
+        let device_id = device_id.clone();
+        let timeout = self.connect_timeout;
+        async move { connect_device(&device_id, kind, preferred_service, timeout).await }
+    }
+
+    fn disconnect(&self, link: &DeviceConnection) -> Result<(), Error> {
+        link.close()
+    }
+
+    // Done once the bytes are handed to the socket. Works on its own handle to the socket,
+    // so the write can be awaited after the manager's lock is released.
+    fn send(
+        &self,
+        device_id: &str,
+        link: &DeviceConnection,
+        data: &[u8],
+    ) -> impl Future<Output = Result<(), BlueTrayError>> + Send + use<> {
+        let socket = match link {
+            DeviceConnection::Rfcomm(socket) => Ok(socket.clone()),
+            DeviceConnection::Gatt { .. } => Err(BlueTrayError::NoRfcommService(device_id.to_string())),
+        };
+        let data = data.to_vec();
+        async move {
+            let writer = DataWriter::CreateDataWriter(&socket?.OutputStream()?)?;
+            writer.WriteBytes(&data)?;
+            let stored = match writer.StoreAsync() {
+                Ok(operation) => operation.await,
+                Err(e) => Err(e),
+            };
+            // Dropping a writer that still owns the stream would close the socket's output
+            writer.DetachStream()?;
+            stored?;
+            Ok(())
+        }
+    }
+
+    fn describe(&self, link: &DeviceConnection) -> String {
+        let description = match link {
+            DeviceConnection::Rfcomm(socket) => socket.Information().and_then(|info| {
+                Ok(format!(
+                    "RFCOMM, remote {} service {}, local {} port {}",
+                    info.RemoteHostName()?.DisplayName()?,
+                    info.RemoteServiceName()?,
+                    info.LocalAddress()?.DisplayName()?,
+                    info.LocalPort()?
+                ))
+            }),
+            DeviceConnection::Gatt { session, .. } => session.SessionStatus().and_then(|status| {
+                Ok(format!("GATT, session {:?}, max PDU {} bytes", status, session.MaxPduSize()?))
+            }),
+        };
+        description.unwrap_or_else(|e| format!("unavailable ({})", e))
+    }
+
+    fn battery(&self, device_id: &HSTRING) -> impl Future<Output = Option<u8>> + Send {
+        let device_id = device_id.clone();
+        async move { read_battery(&device_id).await }
+    }
+
+    fn signal_strength(&self, device_id: &HSTRING) -> impl Future<Output = Option<i16>> + Send {
+        let device_id = device_id.clone();
+        async move { read_rssi(&device_id).await }
+    }
+}
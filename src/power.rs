@@ -0,0 +1,36 @@
+use std::ffi::c_void;
+
+use windows::Win32::Foundation::{ERROR_SUCCESS, HANDLE};
+use windows::Win32::System::Power::{PowerRegisterSuspendResumeNotification, DEVICE_NOTIFY_SUBSCRIBE_PARAMETERS};
+use windows::Win32::UI::WindowsAndMessaging::{DEVICE_NOTIFY_CALLBACK, PBT_APMRESUMEAUTOMATIC};
+
+type ResumeCallback = Box<dyn Fn() + Send + Sync>;
+
+// Calls on_resume, on a system thread, every time the machine wakes up from sleep. The
+// registration lasts as long as the process.
+pub fn watch_resume(on_resume: impl Fn() + Send + Sync + 'static) -> windows::core::Result<()> {
+    // Both are leaked on purpose: Windows keeps calling back with the context until exit
+    let callback: &'static ResumeCallback = Box::leak(Box::new(Box::new(on_resume) as ResumeCallback));
+    let parameters = Box::leak(Box::new(DEVICE_NOTIFY_SUBSCRIBE_PARAMETERS {
+        Callback: Some(power_event),
+        Context: callback as *const ResumeCallback as *mut c_void,
+    }));
+    let mut registration = std::ptr::null_mut();
+    unsafe {
+        PowerRegisterSuspendResumeNotification(
+            DEVICE_NOTIFY_CALLBACK,
+            HANDLE(parameters as *mut DEVICE_NOTIFY_SUBSCRIBE_PARAMETERS as *mut c_void),
+            &mut registration,
+        )
+    }
+    .ok()
+}
+
+unsafe extern "system" fn power_event(context: *const c_void, kind: u32, _setting: *const c_void) -> u32 {
+    // Sent on every wake, whether or not a user is there to see it
+    if kind == PBT_APMRESUMEAUTOMATIC {
+        let on_resume = unsafe { &*(context as *const ResumeCallback) };
+        on_resume();
+    }
+    ERROR_SUCCESS.0
+}
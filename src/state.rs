@@ -0,0 +1,84 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+// What bluetray remembers between runs, stored in %APPDATA%/bluetray/state.json. Settings
+// the user edits live in the config file instead.
+#[derive(Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SavedState {
+    pub connected_devices: Vec<String>,
+    // Most recently connected device, which a left click on the tray icon reconnects
+    pub last_device: Option<String>,
+    // When each device was last in range, in seconds since the Unix epoch
+    pub last_seen: HashMap<String, u64>,
+}
+
+// Presence updates can come in bursts, and minutes are all the menu shows
+const LAST_SEEN_SAVE_INTERVAL_SECS: u64 = 60;
+
+impl SavedState {
+    pub fn load() -> Self {
+        let Some(path) = state_path() else {
+            return Self::default();
+        };
+        match fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+                warn!(path = %path.display(), error = %e, "Ignoring unreadable state file");
+                Self::default()
+            }),
+            Err(_) => Self::default(),
+        }
+    }
+
+    // Saves only when the set actually changed, since this runs on every connection change
+    pub fn set_connected_devices(&mut self, mut device_ids: Vec<String>) {
+        device_ids.sort();
+        if device_ids != self.connected_devices {
+            self.connected_devices = device_ids;
+            self.save();
+        }
+    }
+
+    pub fn last_seen(&self, device_id: &str) -> Option<SystemTime> {
+        self.last_seen
+            .get(device_id)
+            .map(|secs| UNIX_EPOCH + Duration::from_secs(*secs))
+    }
+
+    pub fn set_last_seen(&mut self, device_id: &str, when: SystemTime) {
+        let secs = when.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+        let previous = self.last_seen.insert(device_id.to_string(), secs);
+        if previous.is_none_or(|previous| secs.saturating_sub(previous) >= LAST_SEEN_SAVE_INTERVAL_SECS) {
+            self.save();
+        }
+    }
+
+    pub fn save(&self) {
+        let Some(path) = state_path() else {
+            return;
+        };
+        let result = path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|()| {
+                let contents = serde_json::to_string_pretty(self).map_err(std::io::Error::other)?;
+                fs::write(&path, contents)
+            });
+        if let Err(e) = result {
+            warn!(path = %path.display(), error = %e, "Failed to save state");
+        }
+    }
+}
+
+pub fn app_data_dir() -> Option<PathBuf> {
+    std::env::var_os("APPDATA").map(|dir| PathBuf::from(dir).join("bluetray"))
+}
+
+fn state_path() -> Option<PathBuf> {
+    app_data_dir().map(|dir| dir.join("state.json"))
+}
@@ -0,0 +1,70 @@
+use std::future::Future;
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+
+use tokio::task::{AbortHandle, JoinSet};
+use tracing::{debug, warn};
+
+// Every task the tray starts goes through here rather than tokio::spawn, so shutdown can
+// wait for the ones still working and abort the rest
+static TASKS: LazyLock<Mutex<Tasks>> = LazyLock::new(Mutex::default);
+
+#[derive(Default)]
+struct Tasks {
+    // One-off work, e.g. a connect or a refresh, given until the deadline to finish
+    work: JoinSet<()>,
+    // Tasks that only end when told to, e.g. the health check, aborted right away
+    loops: Vec<AbortHandle>,
+}
+
+pub fn spawn<F>(task: F) -> AbortHandle
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let mut tasks = TASKS.lock().unwrap();
+    // Finished tasks stay in the set until they're joined
+    while tasks.work.try_join_next().is_some() {}
+    tasks.work.spawn(task)
+}
+
+// For blocking calls, e.g. COM. Once running these can't be aborted, only waited for.
+pub fn spawn_blocking<F>(task: F)
+where
+    F: FnOnce() + Send + 'static,
+{
+    let mut tasks = TASKS.lock().unwrap();
+    while tasks.work.try_join_next().is_some() {}
+    tasks.work.spawn_blocking(task);
+}
+
+pub fn spawn_loop<F>(task: F) -> AbortHandle
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let handle = tokio::spawn(task).abort_handle();
+    let mut tasks = TASKS.lock().unwrap();
+    tasks.loops.retain(|loop_handle| !loop_handle.is_finished());
+    tasks.loops.push(handle.clone());
+    handle
+}
+
+// Aborts the loops, waits up to deadline for the rest to finish, then aborts whatever is
+// still running. Returns how many had to be aborted that way.
+pub async fn shutdown(deadline: Duration) -> usize {
+    let Tasks { mut work, loops } = std::mem::take(&mut *TASKS.lock().unwrap());
+    for handle in loops {
+        handle.abort();
+    }
+    debug!(running = work.len(), "Waiting for background tasks");
+    let finished = tokio::time::timeout(deadline, async {
+        while work.join_next().await.is_some() {}
+    })
+    .await;
+    if finished.is_ok() {
+        return 0;
+    }
+    let left = work.len();
+    warn!(left, ?deadline, "Background tasks still running at exit, aborting them");
+    work.abort_all();
+    left
+}